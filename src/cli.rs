@@ -1,9 +1,10 @@
 use std::env;
-use std::io::{Write, Read};
+use std::io::Write;
 use std::fs;
-use std::collections::HashSet;
-use std::path::PathBuf;
-use odysseus_lib::Store;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::error::Error;
+use odysseus_lib::{Store, StoreError, Playlist, Playlists, Repository, Gains, Loudness, Request, Response, Mode};
 use clap::{Arg, App, SubCommand, AppSettings};
 
 fn main() {
@@ -19,6 +20,11 @@ fn main() {
                 .help("Sets the input path")
                 .required(true)
                 .index(1))
+            .arg(Arg::with_name("import")
+                .long("import")
+                .value_name("DIR")
+                .help("Creates a playlist for every folder of music in DIR")
+                .takes_value(true))
         )
         .subcommand(SubCommand::with_name("list")
             .about("List all playlists in music library")
@@ -26,6 +32,103 @@ fn main() {
         .subcommand(SubCommand::with_name("add")
             .about("Add music to the library")
         )
+        .subcommand(SubCommand::with_name("card")
+            .about("Manage which card plays which playlist")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .about("Show the card of every playlist")
+            )
+            .subcommand(SubCommand::with_name("assign")
+                .about("Assign a card id to a playlist")
+                .arg(Arg::with_name("PLAYLIST")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("ID")
+                    .help("Card id, defaults to the next free id")
+                    .index(2))
+            )
+            .subcommand(SubCommand::with_name("unassign")
+                .about("Remove the card of a playlist")
+                .arg(Arg::with_name("PLAYLIST")
+                    .required(true)
+                    .index(1))
+            )
+            .subcommand(SubCommand::with_name("swap")
+                .about("Exchange the cards of two playlists")
+                .arg(Arg::with_name("A")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("B")
+                    .required(true)
+                    .index(2))
+            )
+            .subcommand(SubCommand::with_name("free")
+                .about("Show playlists without card and the next free card id")
+            )
+        )
+        .subcommand(SubCommand::with_name("remote")
+            .about("Control a running Zyklop")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(Arg::with_name("socket")
+                .long("socket")
+                .value_name("PATH")
+                .help("Control socket of the Zyklop, defaults to the one in the current workspace")
+                .takes_value(true))
+            .subcommand(SubCommand::with_name("status")
+                .about("Show what the Zyklop is playing")
+            )
+            .subcommand(SubCommand::with_name("play")
+                .about("Play a playlist, as if its card was put on the reader")
+                .arg(Arg::with_name("PLAYLIST")
+                    .required(true)
+                    .index(1))
+            )
+            .subcommand(SubCommand::with_name("next")
+                .about("Skip to the next track")
+            )
+            .subcommand(SubCommand::with_name("prev")
+                .about("Go back to the previous track")
+            )
+            .subcommand(SubCommand::with_name("pause")
+                .about("Pause the playback")
+            )
+            .subcommand(SubCommand::with_name("resume")
+                .about("Continue a paused playback")
+            )
+            .subcommand(SubCommand::with_name("stop")
+                .about("Stop the playback and remember its position")
+            )
+            .subcommand(SubCommand::with_name("shuffle")
+                .about("Toggle shuffled playback")
+            )
+            .subcommand(SubCommand::with_name("program")
+                .about("Assign the new card on the reader to a playlist")
+                .arg(Arg::with_name("PLAYLIST")
+                    .required(true)
+                    .index(1))
+            )
+            .subcommand(SubCommand::with_name("volume")
+                .about("Set the volume, limited to the maximum volume of the Zyklop")
+                .arg(Arg::with_name("PERCENT")
+                    .required(true)
+                    .index(1))
+            )
+            .subcommand(SubCommand::with_name("playlists")
+                .about("List the playlists known to the Zyklop")
+            )
+        )
+        .subcommand(SubCommand::with_name("analyze")
+            .about("Measure the loudness of new music, to play all playlists equally loud")
+            .arg(Arg::with_name("PLAYLIST")
+                .help("Name of the playlist, defaults to all playlists")
+                .index(1))
+        )
+        .subcommand(SubCommand::with_name("sync")
+            .about("Exchange the library with other Zyklops")
+            .arg(Arg::with_name("REMOTE")
+                .help("Name of the git remote, defaults to all remotes")
+                .index(1))
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -35,22 +138,35 @@ fn main() {
             let mut full_path = PathBuf::from(full_path.to_string());
 
             if !full_path.is_absolute() {
-                full_path = PathBuf::from(env::current_dir().unwrap()).join(full_path);
-            }
-
-            if full_path.join("Music.toml").exists() {
-                eprintln!(" => Workspace in {} already exists!", full_path.to_str().unwrap());
-                return;
+                full_path = env::current_dir().unwrap().join(full_path);
             }
 
             println!(" => Initialize workspace in {}", full_path.to_str().unwrap());
 
-            // create root and files/ folder
-            fs::create_dir_all(&full_path.join("files")).unwrap();
+            let mut store = match Store::create(&full_path) {
+                Ok(store) => store,
+                Err(err) => {
+                    eprintln!(" => {}", error_chain(&err));
+                    process::exit(1);
+                }
+            };
 
-            // create Hex.toml file
-            fs::File::create(&full_path.join("Hex.toml")).unwrap();
+            // scaffold playlists from an existing music collection
+            if let Some(import_path) = sub_match.value_of("import") {
+                let names = match store.import_folders(import_path) {
+                    Ok(names) => names,
+                    Err(err) => {
+                        eprintln!(" => {}", error_chain(&err));
+                        process::exit(1);
+                    }
+                };
+                for name in &names {
+                    println!(" => Imported playlist {}", name);
+                }
+                warn_unsupported(&store, &names);
 
+                store.save_with_message(&format!("Import playlists {}", names.join(", "))).unwrap();
+            }
         },
         ("list", Some(_)) => {
             let store = Store::from_pwd().unwrap();
@@ -58,35 +174,345 @@ fn main() {
                 println!(" => {}", playlist.name);
             }
         }
-        ("add", Some(sub_match)) => {
-            // look for folders which are not in toml
-            // get all playlist names
-            let store = Store::from_pwd().unwrap();
-            let playlist_names: HashSet<String> = store.playlists()
-                .iter().map(|pl| pl.name.clone())
-                .collect();
-            
-            // get all folder names in pwd/files and filter out already known ones
-            let music_files_path = store.root_path().join("files");
-            let folder_names: HashSet<String> = std::fs::read_dir(music_files_path).unwrap()
-                .into_iter()
-                .filter_map(|x| x.ok())
-                .map(|p| p.file_name())
-                .map(|p| p.to_string_lossy().into_owned())
-                .collect();
-            dbg!(&playlist_names, &folder_names);
-            let new_playlist_names = folder_names
-                .difference(&playlist_names);
-
-            dbg!(new_playlist_names);
+        ("add", Some(_)) => {
+            let mut store = Store::from_pwd().unwrap();
+
+            // look for folders which are not in toml yet
+            let new_playlist_names = store.untracked_folders().unwrap();
+            if new_playlist_names.is_empty() {
+                println!(" => No new folders in {}", store.root_path().join("files").to_str().unwrap());
+                return;
+            }
 
             // add playlist entries in toml with folder names as name
+            let new_playlists = Playlists {
+                playlists: new_playlist_names.iter().map(|name| Playlist::new(name)).collect()
+            };
 
             // open editor with part of toml including new playlist entries
+            let fragment_path = env::temp_dir().join(format!("odysseus-add-{}.toml", process::id()));
+            fs::write(&fragment_path, new_playlists.to_toml().unwrap()).unwrap();
+
+            let playlists = loop {
+                if let Err(err) = edit_file(&fragment_path) {
+                    eprintln!(" => Could not run editor: {}", err);
+                    fs::remove_file(&fragment_path).unwrap();
+                    return;
+                }
+
+                // parse and validate the edited playlists, nothing is added if one of them is invalid
+                let source = fs::read_to_string(&fragment_path).unwrap();
+                let res = Playlists::parse(&source).and_then(|pls| {
+                    store.add_playlists(pls.playlists.clone())?;
+
+                    Ok(pls)
+                });
+
+                match res {
+                    Ok(pls) => break pls,
+                    Err(err) => {
+                        eprintln!(" => Invalid playlists: {}", error_chain(&err));
+                        if !ask_yes_no("Edit again?") {
+                            fs::remove_file(&fragment_path).unwrap();
+                            return;
+                        }
+                    }
+                }
+            };
+
+            fs::remove_file(&fragment_path).unwrap();
+
+            let names = playlists.playlists.iter()
+                .map(|pl| pl.name.as_str())
+                .collect::<Vec<_>>();
+
+            for name in &names {
+                println!(" => Added playlist {}", name);
+            }
+            warn_unsupported(&store, &names);
 
             // on closing add and commit to git repo with predefined commit message
+            store.save_with_message(&format!("Add playlists {}", names.join(", "))).unwrap();
         }
+        ("card", Some(sub_match)) => {
+            let mut store = Store::from_pwd().unwrap();
+
+            let res = match sub_match.subcommand() {
+                ("list", Some(_)) => {
+                    print_cards(&store);
+                    Ok(())
+                },
+                ("assign", Some(args)) => {
+                    let name = args.value_of("PLAYLIST").unwrap();
+                    let id = match args.value_of("ID").map(|x| x.parse::<u32>()) {
+                        Some(Ok(id)) => id,
+                        Some(Err(err)) => {
+                            eprintln!(" => Invalid card id: {}", err);
+                            return;
+                        },
+                        None => store.next_card_id(),
+                    };
+
+                    store.set_playlist_card_id(name, id)
+                        .and_then(|_| store.save_with_message(&format!("Assign card {} to playlist {}", id, name)))
+                        .map(|_| println!(" => Assigned card {} to {}, please program the card", id, name))
+                },
+                ("unassign", Some(args)) => {
+                    let name = args.value_of("PLAYLIST").unwrap();
+
+                    store.unassign_card(name)
+                        .and_then(|_| store.save_with_message(&format!("Remove card of playlist {}", name)))
+                        .map(|_| println!(" => Removed card of {}", name))
+                },
+                ("swap", Some(args)) => {
+                    let (a, b) = (args.value_of("A").unwrap(), args.value_of("B").unwrap());
+
+                    store.swap_cards(a, b)
+                        .and_then(|_| store.save_with_message(&format!("Swap cards of playlists {} and {}", a, b)))
+                        .map(|_| println!(" => Swapped cards of {} and {}", a, b))
+                },
+                ("free", Some(_)) => {
+                    for pl in store.playlists_without_card() {
+                        println!(" => {} has no card", pl.name);
+                    }
+                    println!(" => Next free card id is {}", store.next_card_id());
+
+                    Ok(())
+                },
+                _ => Ok(()),
+            };
+
+            if let Err(err) = res {
+                eprintln!(" => {}", error_chain(&err));
+            }
+
+            for (id, names) in store.duplicate_card_ids() {
+                eprintln!(" => Card {} is used by several playlists: {}", id, names.join(", "));
+            }
+        },
+        ("remote", Some(sub_match)) => {
+            let socket = match sub_match.value_of("socket") {
+                Some(path) => PathBuf::from(path),
+                None => odysseus_lib::socket_path(env::current_dir().unwrap()),
+            };
+
+            let playlist = |args: Option<&clap::ArgMatches>| args
+                .and_then(|x| x.value_of("PLAYLIST"))
+                .unwrap_or_default()
+                .to_string();
+
+            let request = match sub_match.subcommand() {
+                ("status", _) => Request::Status,
+                ("play", args) => Request::Play { playlist: playlist(args) },
+                ("next", _) => Request::Next,
+                ("prev", _) => Request::Prev,
+                ("pause", _) => Request::Pause,
+                ("resume", _) => Request::Resume,
+                ("stop", _) => Request::Stop,
+                ("shuffle", _) => Request::Shuffle,
+                ("program", args) => Request::Program { playlist: playlist(args) },
+                ("volume", Some(args)) => match args.value_of("PERCENT").unwrap().parse::<u8>() {
+                    Ok(volume) => Request::SetVolume { volume },
+                    Err(err) => {
+                        eprintln!(" => Invalid volume: {}", err);
+                        return;
+                    },
+                },
+                ("playlists", _) => Request::Playlists,
+                _ => return,
+            };
+
+            match request.send(&socket) {
+                Ok(Response::Ok) => {},
+                Ok(Response::Status(status)) => {
+                    let mode = match status.mode {
+                        Mode::Idle => "Idle",
+                        Mode::Playing => "Playing",
+                        Mode::Paused => "Paused",
+                        Mode::Programming => "Programming a new card",
+                    };
+
+                    println!(" => {}", mode);
+                    if let Some(playlist) = &status.playlist {
+                        println!("    playlist {}{}", playlist, if status.shuffled { ", shuffled" } else { "" });
+                    }
+                    if let (Some(track), Some(position)) = (status.track, status.position) {
+                        println!("    track {} at {}:{:02}", track + 1, position / 60, position % 60);
+                    }
+                    if let Some(file) = &status.file {
+                        println!("    file {}", file);
+                    }
+                    println!("    volume {}%", status.volume);
+                },
+                Ok(Response::Playlists { playlists }) => {
+                    for pl in playlists {
+                        println!(" => {} ({} tracks)", pl.name, pl.files.len());
+                    }
+                },
+                Ok(Response::Error { message }) => eprintln!(" => {}", message),
+                Err(err) => eprintln!(" => {}", error_chain(&err)),
+            }
+        },
+        ("analyze", Some(sub_match)) => {
+            let store = Store::from_pwd().unwrap();
+            let mut gains = Gains::from_path(store.root_path());
+            gains.retain(store.playlists());
+
+            let playlists = match sub_match.value_of("PLAYLIST") {
+                Some(name) => match store.playlists().iter().find(|x| x.name == name) {
+                    Some(pl) => vec![pl],
+                    None => {
+                        eprintln!(" => {}", StoreError::PlaylistNotFound(name.into()));
+                        return;
+                    }
+                },
+                None => store.playlists().iter().collect(),
+            };
+
+            for pl in playlists.into_iter().filter(|x| x.radio_url.is_none()) {
+                let missing = gains.missing(pl).unwrap();
+                if !missing.is_empty() {
+                    println!(" => Analyze {} new files of {}", missing.len(), pl.name);
+                }
+
+                for file in &missing {
+                    match Loudness::from_path(file) {
+                        Ok(loudness) => {
+                            let name = file.file_name().unwrap().to_string_lossy();
+                            if loudness.blocks > 0 {
+                                println!("    {:.1} LUFS {}", loudness.integrated, name);
+                            } else {
+                                println!("    silent {}", name);
+                            }
+
+                            gains.insert(&pl.name, file, loudness).unwrap();
+                        },
+                        Err(err) => eprintln!("    {}", error_chain(&err)),
+                    }
+                }
+
+                // save after every playlist, so that an interrupted analysis is not lost
+                gains.save(store.root_path()).unwrap();
+
+                if let Some(album) = gains.album(&pl.name) {
+                    println!(" => {} has {:.1} LUFS, gain {:+.1} dB", pl.name, album.integrated, album.gain());
+                }
+            }
+
+            gains.save(store.root_path()).unwrap();
+            store.save_with_message("Analyze loudness").unwrap();
+        },
+        ("sync", Some(sub_match)) => {
+            let mut store = Store::from_pwd().unwrap();
+
+            let remotes = match sub_match.value_of("REMOTE") {
+                Some(remote) => vec![remote.to_string()],
+                None => Repository::open(store.root_path())
+                    .map(|repo| repo.remotes().unwrap())
+                    .unwrap_or_default(),
+            };
+
+            if remotes.is_empty() {
+                eprintln!(" => {}", StoreError::NoRemote);
+                return;
+            }
+
+            for remote in remotes {
+                println!(" => Synchronise with {}", remote);
+
+                match store.sync(&remote) {
+                    Ok(report) => {
+                        for file in &report.pulled {
+                            println!("    pulled {}", file);
+                        }
+                        for file in &report.pushed {
+                            println!("    pushed {}", file);
+                        }
+                        for (name, id) in &report.reassigned {
+                            println!("    card of {} changed to {}, please reprogram it", name, id);
+                        }
+                        for name in &report.unassigned {
+                            println!("    card of {} is used by another playlist, please assign a new one", name);
+                        }
+                    },
+                    Err(err) => eprintln!(" => Synchronisation with {} failed: {}", remote, error_chain(&err)),
+                }
+            }
+        },
         _ => {
         }
     }
 }
+
+/// Print a table of all playlists and their cards
+fn print_cards(store: &Store) {
+    let width = store.playlists().iter().map(|x| x.name.chars().count()).max().unwrap_or(0).max(8);
+
+    println!("    {:<width$}  {:>6}  UID", "Playlist", "Card", width = width);
+    for pl in store.playlists() {
+        let id = pl.card_id.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+        let uid = pl.card_uid.as_deref().unwrap_or("-");
+
+        println!("    {:<width$}  {:>6}  {}", pl.name, id, uid, width = width);
+    }
+}
+
+/// Open a file in the editor of the user and wait for it to close
+///
+/// The editor is taken from `$VISUAL` or `$EDITOR` and falls back to `vi`.
+fn edit_file(path: &Path) -> std::io::Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".into());
+
+    // the editor variable may contain additional arguments, like `code --wait`
+    let mut args = editor.split_whitespace();
+    let program = args.next().unwrap_or("vi");
+
+    let status = Command::new(program)
+        .args(args)
+        .arg(path)
+        .status()?;
+
+    if !status.success() {
+        return Err(std::io::Error::other(format!("{} exited with {}", program, status)));
+    }
+
+    Ok(())
+}
+
+/// Ask the user a question on the terminal, defaulting to yes
+fn ask_yes_no(question: &str) -> bool {
+    print!(" => {} [Y/n] ", question);
+    std::io::stdout().flush().unwrap();
+
+    // treat a closed stdin as rejection, otherwise we would ask forever
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => return false,
+        Ok(_) => {}
+    }
+
+    !answer.trim().eq_ignore_ascii_case("n")
+}
+
+/// Format an error together with its sources
+/// Tell about files of new playlists, which are left out because they can't be played
+fn warn_unsupported<T: AsRef<str>>(store: &Store, names: &[T]) {
+    for name in names {
+        for file in store.unsupported_files(name.as_ref()) {
+            eprintln!(" => Skipped {}/{}, Opus can't be played yet", name.as_ref(), file.file_name().unwrap().to_string_lossy());
+        }
+    }
+}
+
+fn error_chain(err: &dyn Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        msg.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+
+    msg
+}
@@ -0,0 +1,113 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::{Result, StoreError};
+
+/// File name of the control socket, inside of the music workspace
+pub const SOCKET: &str = "zyklop.sock";
+
+/// Return the path of the control socket of a workspace
+pub fn socket_path<T: AsRef<Path>>(root_path: T) -> PathBuf {
+    root_path.as_ref().join(SOCKET)
+}
+
+/// Request sent to a running Zyklop
+///
+/// Every request is a single line of JSON, like `{"command":"play","playlist":"Kids"}`, and is
+/// answered by a single line with a `Response`. The commands act like the buttons and cards.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    /// Play a playlist, as if its card was put on the reader
+    Play { playlist: String },
+    Next,
+    Prev,
+    /// Halt the playback, it continues where it was paused
+    Pause,
+    Resume,
+    /// Stop the playback and store its position, like removing the card for longer would
+    Stop,
+    /// Toggle the shuffled playback
+    Shuffle,
+    /// Assign the new card on the reader to a playlist
+    Program { playlist: String },
+    /// Set the volume in percent, limited to the maximum volume of the Zyklop
+    SetVolume { volume: u8 },
+    /// List the playlists of the library
+    Playlists,
+}
+
+/// What the Zyklop is doing, reduced to its name
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Idle,
+    Playing,
+    Paused,
+    Programming,
+}
+
+/// State of a running Zyklop
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Status {
+    pub mode: Mode,
+    #[serde(default)]
+    pub playlist: Option<String>,
+    /// Index of the current track
+    #[serde(default)]
+    pub track: Option<usize>,
+    /// File name of the current track, unknown if mplayer shuffles
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Seconds into the current track
+    #[serde(default)]
+    pub position: Option<u64>,
+    #[serde(default)]
+    pub shuffled: bool,
+    /// Volume in percent
+    pub volume: u8,
+}
+
+/// Playlist of the library, as listed by the control API
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PlaylistInfo {
+    pub name: String,
+    /// File names of the tracks, in playing order
+    pub files: Vec<String>,
+}
+
+/// Answer of a running Zyklop
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(Status),
+    Playlists { playlists: Vec<PlaylistInfo> },
+    Error { message: String },
+}
+
+impl Request {
+    /// Send a request to the Zyklop listening on a socket and wait for the response
+    pub fn send<T: AsRef<Path>>(&self, socket: T) -> Result<Response> {
+        let socket = socket.as_ref();
+        let mut stream = UnixStream::connect(socket)
+            .map_err(|err| StoreError::ControlFailed(socket.to_path_buf(), err))?;
+
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut answer = String::new();
+        if BufReader::new(stream).read_line(&mut answer)? == 0 {
+            // the Zyklop shut down before it answered
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed");
+            return Err(StoreError::ControlFailed(socket.to_path_buf(), err));
+        }
+
+        Ok(serde_json::from_str(&answer)?)
+    }
+}
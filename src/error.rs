@@ -8,24 +8,66 @@ pub type Result<T> = std::result::Result<T, StoreError>;
 pub enum StoreError {
     #[error("configuration file not found in {0}")]
     ConfMissing(PathBuf, #[source] io::Error),
+    #[error("music store in {0} already exists")]
+    StoreExists(PathBuf),
+    #[error("configuration has version {0}, which is newer than this program")]
+    UnsupportedVersion(u32),
     #[error("generic IO error")]
     Io(#[from] io::Error),
     #[error("parsing TOML file failed")]
     TomlParsing(#[from] toml::de::Error),
     #[error("generating TOML file failed")]
     TomlGen(#[from] toml::ser::Error),
+    #[error("invalid control message")]
+    Json(#[from] serde_json::Error),
+    #[error("could not connect to Zyklop at {0}")]
+    ControlFailed(PathBuf, #[source] io::Error),
     #[error("could not find playlist with name {0}")]
     PlaylistNotFound(String),
+    #[error("playlist with name {0} already exists")]
+    PlaylistExists(String),
+    #[error("folder for playlist {0} missing in files/")]
+    PlaylistFolderMissing(String),
+    #[error("card id {0} is already used by another playlist")]
+    CardIdTaken(u32),
+    #[error("card with UID {0} is already used by another playlist")]
+    CardUidTaken(String),
+    #[error("{0} is not a valid card UID")]
+    InvalidCardUid(String),
     #[error("music file not found with name {0}")]
     SongNotFound(String),
     #[error("binary {0} missing")]
     BinaryMissing(String),
+    #[error("git {0} failed with stderr={1}")]
+    GitFailed(String, String),
+    #[error("workspace {0} is not a git repository")]
+    NoRepository(PathBuf),
+    #[error("no remote configured to synchronise with")]
+    NoRemote,
+    #[error("could not merge conflicting changes in {0}")]
+    MergeConflict(String),
+    #[error("transferring {0} failed with stderr={1}")]
+    TransferFailed(String, String),
     #[error("mplayer exited with stderr={0}")]
     MplayerFailed(String),
+    #[error("could not analyze {0}: {1}")]
+    AnalysisFailed(String, String),
+    #[error("playback failed: {0}")]
+    PlaybackFailed(String),
     #[error("reached end of playlist")]
     ReachedEndOfPlaylist,
     #[error("reached beginning of playlist")]
     ReachedBeginningOfPlaylist,
     #[error("randomized playing is disallowed")]
     RandomNotAllowed,
+    #[error("nothing is playing")]
+    NothingPlaying,
+    #[error("playback is paused")]
+    PlaybackPaused,
+    #[error("no new card on the reader")]
+    NoNewCard,
+    #[error("playlist {0} already has a card")]
+    PlaylistHasCard(String),
+    #[error("could not write card: {0}")]
+    CardWriteFailed(String),
 }
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::Result;
+use crate::loudness::Loudness;
+use crate::Playlist;
+
+/// Measured loudness of a single file
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TrackGain {
+    /// Size of the file when it was analyzed, a different size means the file was replaced
+    pub size: u64,
+    /// Integrated loudness in LUFS
+    pub loudness: f64,
+    /// Number of gated blocks, used to weight the track in the album gain
+    pub blocks: u64,
+    pub peak: f32,
+}
+
+impl TrackGain {
+    fn to_loudness(&self) -> Loudness {
+        Loudness { integrated: self.loudness, blocks: self.blocks, peak: self.peak }
+    }
+}
+
+/// Cache of the loudness of all analyzed files, keyed by playlist and file name
+///
+/// The cache is stored in `Gains.toml`. Analyzing takes long, so only new or changed files are
+/// analyzed by `odysseus analyze`. The cache is versioned like `Files.toml`, so a Zyklop
+/// receiving files with `odysseus sync` gets their loudness as well.
+#[derive(Debug, Deserialize, Serialize, Default, PartialEq)]
+pub struct Gains {
+    #[serde(flatten)]
+    pub playlists: BTreeMap<String, BTreeMap<String, TrackGain>>,
+}
+
+impl Gains {
+    /// Return the files of a playlist which were not analyzed yet or changed since
+    pub fn missing(&self, pl: &Playlist) -> Result<Vec<PathBuf>> {
+        let known = self.playlists.get(&pl.name);
+        let mut missing = Vec::new();
+
+        for file in &pl.files {
+            let size = fs::metadata(file)?.len();
+            let cached = known.and_then(|x| x.get(&file_name(file)));
+
+            if cached.map(|x| x.size != size).unwrap_or(true) {
+                missing.push(file.clone());
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Store the loudness of a file of a playlist
+    pub fn insert(&mut self, playlist: &str, file: &Path, loudness: Loudness) -> Result<()> {
+        let gain = TrackGain {
+            size: fs::metadata(file)?.len(),
+            loudness: loudness.integrated,
+            blocks: loudness.blocks,
+            peak: loudness.peak,
+        };
+
+        self.playlists.entry(playlist.into()).or_default().insert(file_name(file), gain);
+
+        Ok(())
+    }
+
+    /// Remove entries of playlists and files which are not in the library anymore
+    pub fn retain(&mut self, playlists: &[Playlist]) {
+        self.playlists.retain(|name, files| match playlists.iter().find(|x| &x.name == name) {
+            Some(pl) => {
+                files.retain(|file, _| pl.files.iter().any(|x| &file_name(x) == file));
+                !files.is_empty()
+            },
+            None => false,
+        });
+    }
+
+    /// Return the loudness of a single track
+    ///
+    /// Files are stored in `/files/<playlist>/`, so the playlist is taken from the folder of the
+    /// file.
+    pub fn track(&self, file: &Path) -> Option<Loudness> {
+        let playlist = file.parent()?.file_name()?.to_string_lossy();
+
+        self.playlists.get(playlist.as_ref())?
+            .get(&file_name(file))
+            .filter(|x| x.blocks > 0)
+            .map(TrackGain::to_loudness)
+    }
+
+    /// Return the loudness of all analyzed tracks of a playlist together
+    pub fn album(&self, playlist: &str) -> Option<Loudness> {
+        let tracks = self.playlists.get(playlist)?
+            .values()
+            .map(TrackGain::to_loudness)
+            .collect::<Vec<_>>();
+
+        Loudness::combine(&tracks)
+    }
+
+    /// Add entries of another cache, overwriting existing ones
+    pub fn extend(&mut self, other: Gains) {
+        for (name, files) in other.playlists {
+            self.playlists.entry(name).or_default().extend(files);
+        }
+    }
+
+    /// Load the cache from `/Gains.toml`
+    ///
+    /// A missing or unreadable cache is treated as empty, the files are analyzed again.
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Gains {
+        fs::read_to_string(path.as_ref().join("Gains.toml")).ok()
+            .and_then(|x| toml::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    /// Write the cache to `/Gains.toml`
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        fs::write(path.as_ref().join("Gains.toml"), toml::to_string(self)?)?;
+
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,214 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::fs;
+
+use crate::error::{Result, StoreError};
+
+/// Content of `.gitignore` in a fresh workspace
+///
+/// Music files are too large to be versioned, they are described by `Files.toml` instead.
+/// Positions and the control socket are specific to a single Zyklop and never shared.
+const GITIGNORE: &str = "/files/\n/Positions.toml\n/zyklop.sock\n";
+
+/// A music workspace which is version controlled with git
+///
+/// This calls the `git` binary for every operation, the same way the Zyklop drives mplayer.
+pub struct Repository {
+    root_path: PathBuf,
+}
+
+impl Repository {
+    /// Open the git repository of a workspace, if there is one
+    pub fn open<T: AsRef<Path>>(path: T) -> Option<Repository> {
+        let root_path = path.as_ref().to_path_buf();
+
+        if root_path.join(".git").exists() {
+            Some(Repository { root_path })
+        } else {
+            None
+        }
+    }
+
+    /// Create a new git repository in a workspace
+    ///
+    /// This also writes a `.gitignore` excluding the music files and device specific state.
+    pub fn init<T: AsRef<Path>>(path: T) -> Result<Repository> {
+        let repo = Repository { root_path: path.as_ref().to_path_buf() };
+
+        repo.git(&["init", "--quiet"])?;
+        fs::write(repo.root_path.join(".gitignore"), GITIGNORE)?;
+
+        // other Zyklops push directly into our checked out branch
+        repo.git(&["config", "receive.denyCurrentBranch", "updateInstead"])?;
+
+        Ok(repo)
+    }
+
+    /// Stage the given paths and commit them
+    ///
+    /// Returns `false` if nothing changed and therefore no commit was created. During a merge a
+    /// commit is always created, because it concludes the merge.
+    pub fn commit(&self, paths: &[&str], message: &str) -> Result<bool> {
+        let existing = paths.iter()
+            .filter(|x| self.root_path.join(x).exists())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut args = vec!["add", "--"];
+        args.extend(existing);
+        self.git(&args)?;
+
+        // `diff --cached --quiet` exits with one if there are staged changes
+        let status = self.command()
+            .args(["diff", "--cached", "--quiet"])
+            .status()
+            .map_err(map_spawn_error)?;
+
+        if status.success() && !self.merge_in_progress() {
+            return Ok(false);
+        }
+
+        self.git(&["commit", "--quiet", "--message", message])?;
+
+        Ok(true)
+    }
+
+    /// Return the names of all configured remotes
+    pub fn remotes(&self) -> Result<Vec<String>> {
+        Ok(self.git(&["remote"])?.lines().map(|x| x.to_string()).collect())
+    }
+
+    /// Return the URL of a remote
+    pub fn remote_url(&self, remote: &str) -> Result<String> {
+        Ok(self.git(&["remote", "get-url", remote])?.trim().to_string())
+    }
+
+    /// Return the name of the checked out branch
+    pub fn current_branch(&self) -> Result<String> {
+        Ok(self.git(&["symbolic-ref", "--short", "HEAD"])?.trim().to_string())
+    }
+
+    /// Download objects and refs from a remote
+    pub fn fetch(&self, remote: &str) -> Result<()> {
+        self.git(&["fetch", "--quiet", remote])?;
+
+        Ok(())
+    }
+
+    /// Upload the checked out branch to a remote
+    pub fn push(&self, remote: &str, branch: &str) -> Result<()> {
+        self.git(&["push", "--quiet", remote, &format!("HEAD:refs/heads/{}", branch)])?;
+
+        Ok(())
+    }
+
+    /// Check whether a revision exists
+    pub fn has_revision(&self, rev: &str) -> bool {
+        self.git(&["rev-parse", "--verify", "--quiet", &format!("{}^{{commit}}", rev)]).is_ok()
+    }
+
+    /// Check whether revision `a` is an ancestor of revision `b`
+    pub fn is_ancestor(&self, a: &str, b: &str) -> bool {
+        self.git(&["merge-base", "--is-ancestor", a, b]).is_ok()
+    }
+
+    /// Return the best common ancestor of two revisions
+    pub fn merge_base(&self, a: &str, b: &str) -> Option<String> {
+        self.git(&["merge-base", a, b]).ok().map(|x| x.trim().to_string())
+    }
+
+    /// Read the content of a file at a given revision
+    pub fn show(&self, rev: &str, path: &str) -> Option<String> {
+        self.git(&["show", &format!("{}:{}", rev, path)]).ok()
+    }
+
+    /// Start merging a revision without committing the result
+    ///
+    /// Conflicts are not reported here, look at `unmerged_files` afterwards.
+    pub fn start_merge(&self, rev: &str) -> Result<()> {
+        let res = self.git(&["merge", "--quiet", "--no-commit", "--no-ff", rev]);
+
+        if !self.merge_in_progress() {
+            res?;
+        }
+
+        Ok(())
+    }
+
+    /// Abort a merge and restore the state before it
+    pub fn abort_merge(&self) -> Result<()> {
+        self.git(&["merge", "--abort"])?;
+
+        Ok(())
+    }
+
+    /// Fast-forward the checked out branch to a revision
+    pub fn fast_forward(&self, rev: &str) -> Result<()> {
+        self.git(&["merge", "--quiet", "--ff-only", rev])?;
+
+        Ok(())
+    }
+
+    /// Check whether we are in the middle of a merge
+    pub fn merge_in_progress(&self) -> bool {
+        self.git(&["rev-parse", "--verify", "--quiet", "MERGE_HEAD"]).is_ok()
+    }
+
+    /// Return all files with unresolved conflicts
+    pub fn unmerged_files(&self) -> Result<Vec<String>> {
+        Ok(self.git(&["diff", "--name-only", "--diff-filter=U"])?
+            .lines().map(|x| x.to_string()).collect())
+    }
+
+    /// Return root path of the repository
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Run git with arguments and return its standard output
+    pub fn git(&self, args: &[&str]) -> Result<String> {
+        let output = self.command()
+            .args(args)
+            .output()
+            .map_err(map_spawn_error)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+            return Err(StoreError::GitFailed(args.join(" "), stderr));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("git");
+        cmd.current_dir(&self.root_path);
+
+        // a Zyklop usually has no identity configured, fall back to a generic one
+        if !has_identity(&self.root_path) {
+            cmd.args(["-c", "user.name=Odysseus", "-c", "user.email=odysseus@localhost"]);
+        }
+
+        cmd
+    }
+}
+
+/// Check whether git knows who is committing
+fn has_identity(path: &Path) -> bool {
+    Command::new("git")
+        .current_dir(path)
+        .args(["config", "user.email"])
+        .output()
+        .map(|x| x.status.success())
+        .unwrap_or(false)
+}
+
+fn map_spawn_error(err: io::Error) -> StoreError {
+    if err.kind() == io::ErrorKind::NotFound {
+        StoreError::BinaryMissing("git".into())
+    } else {
+        StoreError::Io(err)
+    }
+}
@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::env;
@@ -6,42 +5,226 @@ use std::env;
 use std::fs::File;
 use serde::{Serialize, Deserialize};
 
+mod control;
 mod error;
-
+mod gain;
+mod git;
+mod loudness;
+mod manifest;
+mod position;
+mod sync;
+mod track;
+
+pub use control::{Mode, PlaylistInfo, Request, Response, Status, SOCKET, socket_path};
 pub use error::{Result, StoreError};
+pub use gain::{Gains, TrackGain};
+pub use git::Repository;
+pub use loudness::{Loudness, REFERENCE_LOUDNESS};
+pub use manifest::Manifest;
+pub use position::{Position, Positions};
+pub use sync::SyncReport;
+pub use track::{MediaType, Order, SortBy, Track};
+
+/// Stops the playback of a playlist after a while, for stories at bedtime
+///
+/// Written as `sleep_timer = { minutes = 30, finish_track = true }` in `Music.toml`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct SleepTimer {
+    pub minutes: u32,
+    /// Let the current track end once the time is up, instead of fading out
+    #[serde(default)]
+    pub finish_track: bool,
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Playlist {
     pub name: String,
     #[serde(default)]
     pub card_id: Option<u32>,
+    /// UID of a card which can't store an id, like a read-only card or a figurine
+    #[serde(default)]
+    pub card_uid: Option<String>,
     #[serde(default)]
     pub allow_random: bool,
     #[serde(default)]
     pub radio_url: Option<String>,
+    #[serde(default)]
+    pub order: Order,
+    /// Volume in percent when the playlist starts, the Zyklop default is used if unset
+    #[serde(default)]
+    pub volume: Option<u8>,
+    #[serde(default)]
+    pub sleep_timer: Option<SleepTimer>,
     #[serde(skip)]
     pub files: Vec<PathBuf>,
     #[serde(skip)]
+    pub tracks: Vec<Track>,
+    /// Where the playback stopped, as track index and seconds into this track
+    #[serde(skip)]
     pub position: Option<(usize, usize)>,
 }
 
+impl Playlist {
+    /// Create an empty playlist with default properties
+    pub fn new(name: &str) -> Playlist {
+        Playlist {
+            name: name.into(),
+            card_id: None,
+            card_uid: None,
+            allow_random: false,
+            radio_url: None,
+            order: Order::default(),
+            volume: None,
+            sleep_timer: None,
+            files: Vec::new(),
+            tracks: Vec::new(),
+            position: None,
+        }
+    }
+
+    /// Read the music files of this playlist from `/files/<name>/`
+    ///
+    /// Only music files are considered, hidden files, cover images and the like are skipped. The
+    /// metadata of every file is read into `tracks` and both lists are sorted as configured in
+    /// `order`. Radio streams have no files, for them the lists stay empty. The same is true
+    /// for playlists whose files were not transferred to this Zyklop yet.
+    fn load_files(&mut self, root_path: &Path) -> Result<()> {
+        let folder = root_path.join("files").join(&self.name);
+        if self.radio_url.is_some() || !folder.is_dir() {
+            self.files = Vec::new();
+            self.tracks = Vec::new();
+            return Ok(());
+        }
+
+        self.tracks = std::fs::read_dir(folder)?
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| MediaType::is_music(x))
+            .map(Track::from_path)
+            .collect();
+
+        self.order.sort(&mut self.tracks);
+        self.files = self.tracks.iter().map(|x| x.path.clone()).collect();
+
+        Ok(())
+    }
+}
+
+/// A list of playlists, in the same format as they appear in `Music.toml`
+///
+/// This is used to hand a part of the configuration to the user for editing.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Playlists {
+    #[serde(default)]
+    pub playlists: Vec<Playlist>,
+}
+
+impl Playlists {
+    /// Parse playlists from a TOML string
+    pub fn parse(source: &str) -> Result<Playlists> {
+        Ok(toml::from_str(source)?)
+    }
+
+    /// Serialize playlists to a TOML string
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Return next card id, not used by any of the playlists
+    pub fn next_card_id(&self) -> u32 {
+        next_card_id(&self.playlists)
+    }
+}
+
+fn next_card_id(playlists: &[Playlist]) -> u32 {
+    let mut ids = playlists.iter().filter_map(|x| x.card_id).collect::<Vec<_>>();
+    ids.sort();
+
+    for sl in ids.windows(2) {
+        let (a,b) = (sl[0], sl[1]);
+
+        if a+1 != b {
+            return a + 1;
+        }
+    }
+
+    if ids.is_empty() {
+        0
+    } else {
+        ids[ids.len()-1] + 1
+    }
+}
+
+/// Version of the `Music.toml` format written by this library
+pub const SCHEMA_VERSION: u32 = 1;
+
+fn default_version() -> u32 {
+    SCHEMA_VERSION
+}
+
+/// Header of `Music.toml`, parsed before the rest of the file
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default = "default_version")]
+    version: u32,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Store {
     #[serde(skip)]
     root_path: PathBuf,
+    #[serde(default = "default_version")]
+    version: u32,
     #[serde(default)]
     playlists: Vec<Playlist>,
 }
 
 impl Store {
+    /// Create a new, empty music store in a path
+    ///
+    /// This creates the folder `/files/` for music, an empty `/Music.toml` and a git repository
+    /// tracking the configuration.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use odysseus_lib::Store;
+    /// let store = Store::create("/home/lorenz/music/").unwrap();
+    /// ```
+    pub fn create<T: AsRef<Path>>(path: T) -> Result<Store> {
+        let path = path.as_ref();
+
+        if path.join("Music.toml").exists() {
+            return Err(StoreError::StoreExists(path.to_path_buf()));
+        }
+
+        std::fs::create_dir_all(path.join("files"))?;
+
+        let store = Store {
+            root_path: path.to_path_buf(),
+            version: SCHEMA_VERSION,
+            playlists: Vec::new(),
+        };
+
+        // put everything except the music files under version control
+        if Repository::open(path).is_none() {
+            Repository::init(path)?;
+        }
+
+        store.save_with_message("Initialize music workspace")?;
+
+        Ok(store)
+    }
+
     /// Load a music store from a path
     ///
     /// All music is stored inside a single folder. The file `/Music.toml` describes playlists and
-    /// their propertiers. The actual music files are stored inside `/files/*/*.flac`. 
+    /// their propertiers. The actual music files are stored inside `/files/*/`, in one of the
+    /// formats of `MediaType`. Their metadata is read from their tags and they are ordered as
+    /// configured per playlist.
     ///
     /// # Examples
-    /// ```
-    /// use base::Store;
+    /// ```no_run
+    /// use odysseus_lib::Store;
     /// let store = Store::from_path("/home/lorenz/music/").unwrap();
     /// ```
     pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Store> {
@@ -56,21 +239,23 @@ impl Store {
         let mut source = String::new();
         f.read_to_string(&mut source)?;
 
+        // check the version first, a store we do not understand must never be saved
+        let header: Header = toml::from_str(&source)?;
+        if header.version > SCHEMA_VERSION {
+            return Err(StoreError::UnsupportedVersion(header.version));
+        }
+
         // parse and deserialize string to a vector of playlists
         let mut playlists: Store = toml::from_str(&source)?;
         playlists.root_path = path.to_path_buf();
 
+        // resume where the playback stopped before the last shutdown
+        let positions = Positions::from_path(path);
         for pl in &mut playlists.playlists {
-            if pl.radio_url.is_none() {
-                pl.files = std::fs::read_dir(&playlists.root_path.join("files").join(&pl.name)).unwrap()
-                    .filter_map(|x| x.ok())
-                    .map(|x| x.path())
-                    .collect();
-            }
+            pl.load_files(&playlists.root_path)?;
+            positions.restore(pl);
         }
 
-        dbg!(&playlists);
-
         Ok(playlists)
     }
 
@@ -86,23 +271,42 @@ impl Store {
     /// string to the `Music.toml` file. An error may occure when the file can't be open or written
     /// to
     pub fn save(&self) -> Result<()> {
+        self.save_with_message("Update music library")
+    }
+
+    /// Save the playlists configuration and commit it with a message
+    ///
+    /// Besides `Music.toml` this also updates the file manifest `Files.toml`. If the workspace is
+    /// a git repository, both are committed together with the loudness cache `Gains.toml`. No
+    /// commit is created when nothing changed.
+    pub fn save_with_message(&self, message: &str) -> Result<()> {
         let self_str = toml::to_string(&self)?;
 
         let mut f = File::create(self.root_path.join("Music.toml"))
             .map_err(|e| StoreError::ConfMissing(self.root_path.to_path_buf(), e))?;
 
-        f.write(self_str.as_bytes())?;
+        f.write_all(self_str.as_bytes())?;
 
-        let positions = toml::to_string(&self.playlists.iter().filter_map(|x| x.position.map(|a| (x.name.clone(), a))).collect::<Vec<_>>())?;
+        self.save_positions()?;
 
-        let mut f = File::create(self.root_path.join("Positions.toml"))
-            .map_err(|e| StoreError::ConfMissing(self.root_path.to_path_buf(), e))?;
+        let mut manifest = Manifest::from_path(&self.root_path)?;
+        manifest.update(&self.playlists, &self.root_path)?;
+        manifest.save(&self.root_path)?;
 
-        f.write(positions.as_bytes())?;
+        if let Some(repo) = Repository::open(&self.root_path) {
+            repo.commit(&["Music.toml", "Files.toml", "Gains.toml", ".gitignore"], message)?;
+        }
 
         Ok(())
     }
 
+    /// Write the playback positions to `/Positions.toml`
+    ///
+    /// Unlike `save` this commits nothing, because positions belong to a single Zyklop.
+    pub fn save_positions(&self) -> Result<()> {
+        Positions::from_playlists(&self.playlists).save(&self.root_path)
+    }
+
     /// Return a vector of all playlists
     pub fn playlists(&self) -> &[Playlist] {
         &self.playlists 
@@ -115,70 +319,243 @@ impl Store {
 
     /// Return all playlists which do not have a card
     pub fn playlists_without_card(&self) -> Vec<Playlist> {
-        self.playlists.iter().filter(|x| x.card_id.is_none())
+        self.playlists.iter().filter(|x| x.card_id.is_none() && x.card_uid.is_none())
             .cloned()
             .collect()
     }
 
-    /// Return next card id, not used by anyone
-    pub fn next_card_id(&self) -> u32 {
-        let mut ids = self.playlists.iter().filter_map(|x| x.card_id).collect::<Vec<_>>();
-        ids.sort();
+    /// Return the names of all folders in `/files/` which are not yet a playlist
+    pub fn untracked_folders(&self) -> Result<Vec<String>> {
+        let mut folders = std::fs::read_dir(self.root_path.join("files"))?
+            .filter_map(|x| x.ok())
+            .filter(|x| x.path().is_dir())
+            .map(|x| x.file_name().to_string_lossy().into_owned())
+            .filter(|x| !x.starts_with('.'))
+            .filter(|x| !self.playlists.iter().any(|pl| &pl.name == x))
+            .collect::<Vec<_>>();
+
+        folders.sort();
+
+        Ok(folders)
+    }
+
+    /// Add new playlists to the store
+    ///
+    /// The playlists are validated before insertion: their names, card ids and UIDs have to be unique
+    /// and, unless it is a radio stream, a folder with the same name has to exist in `/files/`.
+    /// If a single playlist is invalid, none of them is added.
+    pub fn add_playlists(&mut self, mut playlists: Vec<Playlist>) -> Result<()> {
+        for i in 0..playlists.len() {
+            let (known, rest) = playlists.split_at_mut(i);
+            let pl = &mut rest[0];
+            let others = || self.playlists.iter().chain(known.iter());
+
+            if others().any(|x| x.name == pl.name) {
+                return Err(StoreError::PlaylistExists(pl.name.clone()));
+            }
+
+            if let Some(id) = pl.card_id {
+                if others().any(|x| x.card_id == Some(id)) {
+                    return Err(StoreError::CardIdTaken(id));
+                }
+            }
 
-        for sl in ids.windows(2) {
-            let (a,b) = (sl[0], sl[1]);
+            // UIDs are compared in the format of `format_uid`
+            if let Some(uid) = &pl.card_uid {
+                let uid = format_uid(&parse_uid(uid)?);
+                if others().any(|x| x.card_uid.as_ref() == Some(&uid)) {
+                    return Err(StoreError::CardUidTaken(uid));
+                }
 
-            if a+1 != b {
-                return a + 1;
+                pl.card_uid = Some(uid);
             }
+
+            if pl.radio_url.is_none() && !self.root_path.join("files").join(&pl.name).is_dir() {
+                return Err(StoreError::PlaylistFolderMissing(pl.name.clone()));
+            }
+
+            pl.load_files(&self.root_path)?;
         }
 
-        if ids.len() == 0 {
-            0
-        } else {
-            return ids[ids.len()-1] + 1;
+        self.playlists.append(&mut playlists);
+
+        Ok(())
+    }
+
+    /// Import every folder of music in a path as a new playlist
+    ///
+    /// The files are copied into `/files/<folder name>/`. Folders with the name of an existing
+    /// playlist are skipped. Returns the names of the new playlists.
+    pub fn import_folders<T: AsRef<Path>>(&mut self, path: T) -> Result<Vec<String>> {
+        let mut folders = std::fs::read_dir(path)?
+            .filter_map(|x| x.ok())
+            .filter(|x| x.path().is_dir())
+            .filter(|x| !x.file_name().to_string_lossy().starts_with('.'))
+            .collect::<Vec<_>>();
+
+        folders.sort_by_key(|x| x.file_name());
+
+        let mut playlists = Vec::new();
+        for folder in folders {
+            let name = folder.file_name().to_string_lossy().into_owned();
+            if self.playlists.iter().any(|x| x.name == name) {
+                continue;
+            }
+
+            let target = self.root_path.join("files").join(&name);
+            std::fs::create_dir_all(&target)?;
+
+            for file in std::fs::read_dir(folder.path())?.filter_map(|x| x.ok()) {
+                if file.path().is_file() && !file.file_name().to_string_lossy().starts_with('.') {
+                    std::fs::copy(file.path(), target.join(file.file_name()))?;
+                }
+            }
+
+            playlists.push(Playlist::new(&name));
         }
+
+        let names = playlists.iter().map(|x| x.name.clone()).collect();
+        self.add_playlists(playlists)?;
+
+        Ok(names)
+    }
+
+    /// Return next card id, not used by anyone
+    pub fn next_card_id(&self) -> u32 {
+        next_card_id(&self.playlists)
     }
 
     /// Search for a playlist with a name
     pub fn playlist_by_name(&mut self, name: &str) -> Result<&mut Playlist> {
         self.playlists.iter_mut()
-            .filter(|x| x.name == name)
-            .next()
+            .find(|x| x.name == name)
             .ok_or(StoreError::PlaylistNotFound(name.into()))
     }
     ///
     /// Search for a playlist by the playlist ID
     pub fn playlist_by_card(&mut self, id: u32) -> Result<&mut Playlist> {
         self.playlists.iter_mut()
-            .filter(|x| x.card_id.map(|x| x == id).unwrap_or(false))
-            .next()
+            .find(|x| x.card_id.map(|x| x == id).unwrap_or(false))
             .ok_or(StoreError::PlaylistNotFound(format!("card {}", id)))
     }
 
+    /// Search for a playlist by the UID of its card
+    pub fn playlist_by_card_uid(&mut self, uid: &str) -> Result<&mut Playlist> {
+        self.playlists.iter_mut()
+            .find(|x| x.card_uid.as_deref() == Some(uid))
+            .ok_or(StoreError::PlaylistNotFound(format!("card {}", uid)))
+    }
+
     /// Get files from folder
     pub fn get_files(&self, name: &str) -> Vec<PathBuf> {
         self.playlists.iter()
-            .filter(|x| x.name == name)
-            .next()
+            .find(|x| x.name == name)
             .ok_or(StoreError::PlaylistNotFound(name.into()))
             .map(|x| x.files.clone())
             .unwrap_or(vec![])
     }
 
-    /// Set playlist card id
+    /// Return the files in the folder of a playlist, which are left out because they can't be played
+    pub fn unsupported_files(&self, name: &str) -> Vec<PathBuf> {
+        let mut files = match std::fs::read_dir(self.root_path.join("files").join(name)) {
+            Ok(entries) => entries.filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| MediaType::is_unsupported(x))
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        files.sort();
+
+        files
+    }
+
+    /// Set playlist card id, the id must not be used by another playlist
     pub fn set_playlist_card_id(&mut self, name: &str, id: u32) -> Result<()> {
+        if self.playlists.iter().any(|x| x.name != name && x.card_id == Some(id)) {
+            return Err(StoreError::CardIdTaken(id));
+        }
+
         self.playlist_by_name(name)?.card_id = Some(id);
 
         Ok(())
     }
 
-    /// Set playlist card id
-    pub fn set_position(&mut self, name: &str, pos: usize) -> Result<()> {
-        self.playlist_by_name(name)?.position = Some((pos, 0));
+    /// Remove card id and UID of a playlist
+    pub fn unassign_card(&mut self, name: &str) -> Result<()> {
+        let pl = self.playlist_by_name(name)?;
+        pl.card_id = None;
+        pl.card_uid = None;
+
+        Ok(())
+    }
+
+    /// Exchange the cards of two playlists
+    pub fn swap_cards(&mut self, a: &str, b: &str) -> Result<()> {
+        let find = |name: &str| self.playlists.iter()
+            .position(|x| x.name == name)
+            .ok_or_else(|| StoreError::PlaylistNotFound(name.into()));
+
+        let (a, b) = (find(a)?, find(b)?);
+        let card = (self.playlists[a].card_id, self.playlists[a].card_uid.clone());
+
+        self.playlists[a].card_id = self.playlists[b].card_id;
+        self.playlists[a].card_uid = self.playlists[b].card_uid.take();
+        self.playlists[b].card_id = card.0;
+        self.playlists[b].card_uid = card.1;
+
+        Ok(())
+    }
+
+    /// Return card ids used by more than one playlist, with the names of these playlists
+    ///
+    /// The store never assigns an id twice, but `Music.toml` may be edited by hand.
+    pub fn duplicate_card_ids(&self) -> Vec<(u32, Vec<String>)> {
+        let mut ids = std::collections::BTreeMap::<u32, Vec<String>>::new();
+        for pl in &self.playlists {
+            if let Some(id) = pl.card_id {
+                ids.entry(id).or_default().push(pl.name.clone());
+            }
+        }
+
+        ids.into_iter().filter(|(_, names)| names.len() > 1).collect()
+    }
+
+    /// Set the card UID of a playlist, the UID must not be used by another playlist
+    pub fn set_playlist_card_uid(&mut self, name: &str, uid: &str) -> Result<()> {
+        let uid = format_uid(&parse_uid(uid)?);
+        if self.playlists.iter().any(|x| x.name != name && x.card_uid.as_ref() == Some(&uid)) {
+            return Err(StoreError::CardUidTaken(uid));
+        }
+
+        self.playlist_by_name(name)?.card_uid = Some(uid);
 
         Ok(())
     }
+
+    /// Remember where the playback of a playlist stopped
+    pub fn set_position(&mut self, name: &str, track: usize, seconds: usize) -> Result<()> {
+        self.playlist_by_name(name)?.position = Some((track, seconds));
+
+        Ok(())
+    }
+}
+
+/// Format the UID of a card as lowercase hex, the way it is stored in `Music.toml`
+pub fn format_uid(uid: &[u8]) -> String {
+    uid.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Parse a UID in hex, with optional colons between the bytes
+pub fn parse_uid(uid: &str) -> Result<Vec<u8>> {
+    let digits = uid.chars().filter(|x| *x != ':').collect::<Vec<_>>();
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err(StoreError::InvalidCardUid(uid.into()));
+    }
+
+    digits.chunks(2)
+        .map(|x| u8::from_str_radix(&x.iter().collect::<String>(), 16))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| StoreError::InvalidCardUid(uid.into()))
 }
 
 impl Drop for Store {
@@ -188,3 +565,164 @@ impl Drop for Store {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Return an empty directory for a test, left overs of earlier runs are removed
+    pub fn test_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("odysseus-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    /// Create a store with an empty folder for each of the playlist names
+    pub fn store_with_folders(name: &str, folders: &[&str]) -> Store {
+        let store = Store::create(test_dir(name)).unwrap();
+        for folder in folders {
+            std::fs::create_dir_all(store.root_path().join("files").join(folder)).unwrap();
+        }
+
+        store
+    }
+
+    fn playlist(name: &str, card_id: Option<u32>) -> Playlist {
+        Playlist { card_id, ..Playlist::new(name) }
+    }
+
+    #[test]
+    fn parse_playlists() {
+        let playlists = Playlists::parse("[[playlists]]\nname = \"book\"\ncard_id = 3\n\n[[playlists]]\nname = \"radio\"\nradio_url = \"http://radio\"\n").unwrap();
+
+        assert_eq!(playlists.playlists.len(), 2);
+        assert_eq!(playlists.playlists[0].name, "book");
+        assert_eq!(playlists.playlists[0].card_id, Some(3));
+        assert_eq!(playlists.playlists[1].radio_url.as_deref(), Some("http://radio"));
+
+        assert!(Playlists::parse("").unwrap().playlists.is_empty());
+        assert!(Playlists::parse("[[playlists]]\ncard_id = 3\n").is_err());
+    }
+
+    #[test]
+    fn add_playlists_requires_folder() {
+        let mut store = store_with_folders("add-folder", &["book"]);
+
+        assert!(matches!(store.add_playlists(vec![playlist("book", None), playlist("missing", None)]),
+            Err(StoreError::PlaylistFolderMissing(name)) if name == "missing"));
+        assert!(store.playlists().is_empty());
+
+        // radio streams have no folder
+        let radio = Playlist { radio_url: Some("http://radio".into()), ..Playlist::new("radio") };
+        store.add_playlists(vec![playlist("book", None), radio]).unwrap();
+        assert_eq!(store.playlists().len(), 2);
+    }
+
+    #[test]
+    fn report_unsupported_files() {
+        let mut store = store_with_folders("unsupported", &["book"]);
+        for file in ["2.opus", "1.opus", "3.mp3", "cover.jpg"] {
+            std::fs::write(store.root_path().join("files/book").join(file), "").unwrap();
+        }
+        store.add_playlists(vec![playlist("book", None)]).unwrap();
+
+        let names = store.unsupported_files("book").iter()
+            .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["1.opus", "2.opus"]);
+        assert_eq!(store.playlists()[0].files.len(), 1);
+        assert!(store.unsupported_files("missing").is_empty());
+    }
+
+    #[test]
+    fn add_playlists_rejects_duplicates() {
+        let mut store = store_with_folders("add-duplicate", &["a", "b", "c"]);
+        store.add_playlists(vec![playlist("a", Some(3))]).unwrap();
+
+        assert!(matches!(store.add_playlists(vec![playlist("b", Some(3))]), Err(StoreError::CardIdTaken(3))));
+        assert!(matches!(store.add_playlists(vec![playlist("b", Some(4)), playlist("c", Some(4))]), Err(StoreError::CardIdTaken(4))));
+        assert!(matches!(store.add_playlists(vec![playlist("a", None)]), Err(StoreError::PlaylistExists(_))));
+
+        let with_uid = |name: &str, uid: &str| Playlist { card_uid: Some(uid.into()), ..Playlist::new(name) };
+        assert!(matches!(store.add_playlists(vec![with_uid("b", "04:A2:B3"), with_uid("c", "04a2b3")]),
+            Err(StoreError::CardUidTaken(uid)) if uid == "04a2b3"));
+
+        assert_eq!(store.playlists().len(), 1);
+    }
+
+    fn cards(store: &Store) -> Vec<(Option<u32>, Option<&str>)> {
+        store.playlists().iter().map(|x| (x.card_id, x.card_uid.as_deref())).collect()
+    }
+
+    /// Store with the playlists `a` with card 0, `b` with a card UID and `c` without a card
+    fn store_with_cards(name: &str) -> Store {
+        let mut store = store_with_folders(name, &["a", "b", "c"]);
+        store.add_playlists(vec![playlist("a", Some(0)), Playlist::new("b"), playlist("c", None)]).unwrap();
+        store.set_playlist_card_uid("b", "04:A2:B3:C4").unwrap();
+
+        store
+    }
+
+    #[test]
+    fn set_card_id() {
+        let mut store = store_with_cards("set-card");
+
+        assert!(matches!(store.set_playlist_card_id("c", 0), Err(StoreError::CardIdTaken(0))));
+        assert!(matches!(store.set_playlist_card_uid("c", "04a2b3c4"), Err(StoreError::CardUidTaken(_))));
+        assert!(matches!(store.set_playlist_card_id("d", 1), Err(StoreError::PlaylistNotFound(_))));
+
+        // a playlist may keep its own id
+        store.set_playlist_card_id("a", 0).unwrap();
+        store.set_playlist_card_id("c", 1).unwrap();
+        assert_eq!(cards(&store), [(Some(0), None), (None, Some("04a2b3c4")), (Some(1), None)]);
+        assert_eq!(store.next_card_id(), 2);
+    }
+
+    #[test]
+    fn unassign_card() {
+        let mut store = store_with_cards("unassign-card");
+        store.set_playlist_card_id("b", 1).unwrap();
+
+        store.unassign_card("b").unwrap();
+        store.unassign_card("c").unwrap();
+        assert_eq!(cards(&store), [(Some(0), None), (None, None), (None, None)]);
+        assert!(matches!(store.unassign_card("d"), Err(StoreError::PlaylistNotFound(_))));
+
+        // the card can be used again
+        store.set_playlist_card_uid("c", "04a2b3c4").unwrap();
+        assert_eq!(store.next_card_id(), 1);
+    }
+
+    #[test]
+    fn swap_cards() {
+        let mut store = store_with_cards("swap-cards");
+
+        store.swap_cards("a", "b").unwrap();
+        assert_eq!(cards(&store), [(None, Some("04a2b3c4")), (Some(0), None), (None, None)]);
+
+        // with a playlist without a card, the card moves over
+        store.swap_cards("b", "c").unwrap();
+        assert_eq!(cards(&store), [(None, Some("04a2b3c4")), (None, None), (Some(0), None)]);
+
+        store.swap_cards("a", "a").unwrap();
+        assert_eq!(cards(&store)[0], (None, Some("04a2b3c4")));
+        assert!(matches!(store.swap_cards("a", "d"), Err(StoreError::PlaylistNotFound(name)) if name == "d"));
+        assert!(store.duplicate_card_ids().is_empty());
+    }
+
+    #[test]
+    fn duplicate_card_ids() {
+        let store = store_with_cards("duplicate-cards");
+        let path = store.root_path().to_path_buf();
+        drop(store);
+
+        // ids are only assigned twice by editing `Music.toml`
+        let music = std::fs::read_to_string(path.join("Music.toml")).unwrap();
+        std::fs::write(path.join("Music.toml"), music.replace("name = \"c\"", "name = \"c\"\ncard_id = 0")).unwrap();
+
+        let store = Store::from_path(&path).unwrap();
+        assert_eq!(store.duplicate_card_ids(), [(0, vec!["a".to_string(), "c".to_string()])]);
+    }
+}
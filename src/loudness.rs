@@ -0,0 +1,347 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::path::Path;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::error::{Result, StoreError};
+
+/// Loudness all tracks are normalized to, the reference level of ReplayGain 2.0
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Loudness of silence, blocks below are ignored
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks quieter than the average by this many LU are ignored
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Loudness of a single track as defined by EBU R128
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// Number of 400 ms blocks which passed the gates, zero for silent tracks
+    pub blocks: u64,
+    /// Highest absolute sample value, 1.0 is full scale
+    pub peak: f32,
+}
+
+impl Loudness {
+    /// Measure the loudness of a music file
+    ///
+    /// The file is decoded completely, which takes a while for long audiobooks. The signal is
+    /// K-weighted and cut into blocks of 400 ms overlapping by 75 %, which are gated as
+    /// described in EBU R128.
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Loudness> {
+        let path = path.as_ref();
+        let failed = |err: &dyn std::fmt::Display| StoreError::AnalysisFailed(path.to_string_lossy().into_owned(), err.to_string());
+
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|err| failed(&err))?;
+
+        let track = probed.format.default_track()
+            .ok_or_else(|| failed(&"no audio track found"))?;
+        let track_id = track.id;
+
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|err| failed(&err))?;
+
+        // filters are created with the first packet, when the sample rate is known for sure
+        let mut filters: Vec<(Biquad, Biquad)> = Vec::new();
+        let mut samples: Option<SampleBuffer<f32>> = None;
+
+        // sum of squares of the current 100 ms step and the mean squares of all finished steps
+        let mut step_len = 0;
+        let mut step_pos = 0;
+        let mut step_sum = 0.0;
+        let mut steps = Vec::new();
+        let mut peak = 0.0f32;
+
+        loop {
+            let packet = match probed.format.next_packet() {
+                Ok(packet) => packet,
+                Err(DecodeError::IoError(_)) | Err(DecodeError::ResetRequired) => break,
+                Err(err) => return Err(failed(&err)),
+            };
+
+            if packet.track_id() != track_id {
+                continue;
+            }
+
+            let decoded = match decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(DecodeError::DecodeError(_)) => continue,
+                Err(err) => return Err(failed(&err)),
+            };
+
+            let spec = *decoded.spec();
+            let channels = spec.channels.count();
+            if filters.is_empty() {
+                filters = vec![k_weighting(spec.rate); channels];
+                step_len = spec.rate as usize / 10;
+            }
+
+            let buffer = samples.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+            if buffer.capacity() < decoded.capacity() * channels {
+                *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+            }
+            buffer.copy_interleaved_ref(decoded);
+
+            for frame in buffer.samples().chunks(channels) {
+                for (i, (sample, (shelf, high_pass))) in frame.iter().zip(filters.iter_mut()).enumerate() {
+                    peak = peak.max(sample.abs());
+
+                    let weighted = high_pass.process(shelf.process(*sample as f64));
+                    step_sum += channel_weight(i, channels) * weighted * weighted;
+                }
+
+                step_pos += 1;
+                if step_pos == step_len {
+                    steps.push(step_sum / step_len as f64);
+                    step_pos = 0;
+                    step_sum = 0.0;
+                }
+            }
+        }
+
+        // every block spans four steps
+        let blocks = steps.windows(4)
+            .map(|x| x.iter().sum::<f64>() / 4.0)
+            .filter(|x| loudness(*x) > ABSOLUTE_GATE)
+            .collect::<Vec<_>>();
+
+        if blocks.is_empty() {
+            return Ok(Loudness { integrated: ABSOLUTE_GATE, blocks: 0, peak });
+        }
+
+        let threshold = loudness(blocks.iter().sum::<f64>() / blocks.len() as f64) + RELATIVE_GATE;
+        let gated = blocks.into_iter()
+            .filter(|x| loudness(*x) > threshold)
+            .collect::<Vec<_>>();
+
+        let integrated = loudness(gated.iter().sum::<f64>() / gated.len() as f64);
+
+        Ok(Loudness { integrated, blocks: gated.len() as u64, peak })
+    }
+
+    /// Gain in dB bringing the track to the reference loudness
+    pub fn gain(&self) -> f64 {
+        REFERENCE_LOUDNESS - self.integrated
+    }
+
+    /// Linear factor applying the gain, reduced where the peak would clip
+    pub fn factor(&self) -> f32 {
+        let factor = 10f64.powf(self.gain() / 20.0) as f32;
+
+        if self.peak > 0.0 {
+            factor.min(1.0 / self.peak)
+        } else {
+            factor
+        }
+    }
+
+    /// Integrated loudness of several tracks played one after another
+    ///
+    /// This is the energy average of the tracks, weighted by their gated blocks. It differs
+    /// slightly from measuring all tracks at once, because every track was gated on its own.
+    pub fn combine(tracks: &[Loudness]) -> Option<Loudness> {
+        let blocks = tracks.iter().map(|x| x.blocks).sum::<u64>();
+        if blocks == 0 {
+            return None;
+        }
+
+        let energy = tracks.iter()
+            .map(|x| x.blocks as f64 * energy(x.integrated))
+            .sum::<f64>() / blocks as f64;
+
+        let peak = tracks.iter().map(|x| x.peak).fold(0.0, f32::max);
+
+        Some(Loudness { integrated: loudness(energy), blocks, peak })
+    }
+}
+
+/// Second order IIR filter in direct form I
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+/// The K-weighting filter of ITU-R BS.1770, a high shelf followed by a high pass
+///
+/// The coefficients are derived for every sample rate, the standard only lists them for 48 kHz.
+fn k_weighting(rate: u32) -> (Biquad, Biquad) {
+    let rate = rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    (shelf, high_pass)
+}
+
+/// Weight of a channel in the sum of all channels
+///
+/// Only 5.1 layouts have a low frequency channel, which is ignored, and surround channels, which
+/// are louder than the front.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::tests::test_dir;
+
+    const RATE: u32 = 48000;
+
+    /// Write a 16 bit WAV file, the channels are interleaved
+    fn write_wav(path: &Path, channels: u16, samples: &[f64]) {
+        let data = samples.iter()
+            .flat_map(|x| ((x * i16::MAX as f64).round() as i16).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let mut f = File::create(path).unwrap();
+        f.write_all(b"RIFF").unwrap();
+        f.write_all(&(36 + data.len() as u32).to_le_bytes()).unwrap();
+        f.write_all(b"WAVEfmt ").unwrap();
+        f.write_all(&16u32.to_le_bytes()).unwrap();
+        f.write_all(&1u16.to_le_bytes()).unwrap();
+        f.write_all(&channels.to_le_bytes()).unwrap();
+        f.write_all(&RATE.to_le_bytes()).unwrap();
+        f.write_all(&(RATE * channels as u32 * 2).to_le_bytes()).unwrap();
+        f.write_all(&(channels * 2).to_le_bytes()).unwrap();
+        f.write_all(&16u16.to_le_bytes()).unwrap();
+        f.write_all(b"data").unwrap();
+        f.write_all(&(data.len() as u32).to_le_bytes()).unwrap();
+        f.write_all(&data).unwrap();
+    }
+
+    /// Samples of a 997 Hz sine with a peak level in dBFS
+    fn sine(level: f64, seconds: f64) -> Vec<f64> {
+        let amplitude = 10f64.powf(level / 20.0);
+
+        (0..(seconds * RATE as f64) as usize)
+            .map(|i| amplitude * (2.0 * PI * 997.0 * i as f64 / RATE as f64).sin())
+            .collect()
+    }
+
+    fn measure(name: &str, channels: u16, samples: &[f64]) -> Loudness {
+        let path = test_dir(&format!("loudness-{}", name)).join("test.wav");
+        write_wav(&path, channels, samples);
+
+        Loudness::from_path(path).unwrap()
+    }
+
+    #[test]
+    fn sine_wave() {
+        // BS.1770 gives -3.01 LKFS for a full scale 997 Hz sine in a single channel
+        let loudness = measure("sine", 1, &sine(-20.0, 10.0));
+        assert!((loudness.integrated + 23.01).abs() < 0.05, "{:?}", loudness);
+        assert!((loudness.peak - 0.1).abs() < 0.001);
+        assert_eq!(loudness.blocks, 97);
+
+        // the same sine in both channels is twice as loud
+        let stereo = sine(-20.0, 10.0).into_iter().flat_map(|x| [x, x]).collect::<Vec<_>>();
+        let loudness = measure("stereo", 2, &stereo);
+        assert!((loudness.integrated + 20.0).abs() < 0.05, "{:?}", loudness);
+        assert!((loudness.gain() - 2.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn silence() {
+        let loudness = measure("silence", 1, &vec![0.0; RATE as usize * 3]);
+
+        assert_eq!(loudness, Loudness { integrated: ABSOLUTE_GATE, blocks: 0, peak: 0.0 });
+        assert_eq!(Loudness::combine(&[loudness]), None);
+    }
+
+    #[test]
+    fn gates() {
+        // silence is removed by the absolute gate, a quiet passage by the relative one
+        let mut samples = sine(-20.0, 5.0);
+        samples.extend(vec![0.0; RATE as usize * 5]);
+        samples.extend(sine(-50.0, 5.0));
+
+        // only the blocks overlapping the end of the loud sine pass, slightly lowering the level
+        let loudness = measure("gates", 1, &samples);
+        assert!((loudness.integrated + 23.01).abs() < 0.2, "{:?}", loudness);
+        assert_eq!(loudness.blocks, 50);
+    }
+
+    #[test]
+    fn combine_tracks() {
+        let quiet = Loudness { integrated: -30.0, blocks: 10, peak: 0.1 };
+        let loud = Loudness { integrated: -20.0, blocks: 10, peak: 0.5 };
+
+        let album = Loudness::combine(&[quiet, loud]).unwrap();
+        assert!((album.integrated - 10.0 * ((0.001 + 0.01) / 2f64).log10()).abs() < 0.001, "{:?}", album);
+        assert_eq!((album.blocks, album.peak), (20, 0.5));
+
+        // the gain is limited by the peak
+        assert!((quiet.factor() - 10f32.powf(12.0 / 20.0)).abs() < 0.001);
+        let peaking = Loudness { peak: 0.5, ..quiet };
+        assert_eq!(peaking.factor(), 1.0 / 0.5);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::fs;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::Result;
+use crate::Playlist;
+
+/// List of music files with their sizes, grouped by playlist
+///
+/// The music files themselves are not versioned. Instead `Files.toml` records which files belong
+/// to the library, so that other Zyklops know what they are missing.
+#[derive(Debug, Deserialize, Serialize, Default, PartialEq)]
+pub struct Manifest {
+    #[serde(flatten)]
+    pub playlists: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Manifest {
+    /// Update the manifest with the files of a list of playlists
+    ///
+    /// Entries of playlists which are not in the list anymore are removed. Playlists without a
+    /// folder in `/files/` keep their entries, because their music was not transferred yet.
+    pub fn update(&mut self, playlists: &[Playlist], root_path: &Path) -> Result<()> {
+        self.playlists.retain(|name, _| playlists.iter().any(|x| &x.name == name && x.radio_url.is_none()));
+
+        for pl in playlists.iter().filter(|x| x.radio_url.is_none()) {
+            if !root_path.join("files").join(&pl.name).is_dir() {
+                continue;
+            }
+
+            let mut files = BTreeMap::new();
+            for file in &pl.files {
+                let name = file.file_name()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                files.insert(name, fs::metadata(file)?.len());
+            }
+
+            self.playlists.insert(pl.name.clone(), files);
+        }
+
+        Ok(())
+    }
+
+    /// Parse a manifest from a TOML string
+    pub fn parse(source: &str) -> Result<Manifest> {
+        Ok(toml::from_str(source)?)
+    }
+
+    /// Add entries of another manifest, overwriting existing ones
+    pub fn extend(&mut self, other: Manifest) {
+        for (name, files) in other.playlists {
+            self.playlists.entry(name).or_default().extend(files);
+        }
+    }
+
+    /// Load a manifest from `/Files.toml`, if the workspace has one
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Manifest> {
+        let path = path.as_ref().join("Files.toml");
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+
+        Manifest::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Write the manifest to `/Files.toml`
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        fs::write(path.as_ref().join("Files.toml"), toml::to_string(self)?)?;
+
+        Ok(())
+    }
+}
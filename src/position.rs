@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::fs;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::Result;
+use crate::Playlist;
+
+/// Where the playback of a single playlist stopped
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Position {
+    /// File name of the track, which finds the track again after the playlist was reordered
+    pub file: String,
+    /// Index of the track, used if the file is gone
+    pub track: usize,
+    /// Seconds into the track
+    #[serde(default)]
+    pub seconds: usize,
+}
+
+/// Playback positions of all playlists, keyed by playlist name
+///
+/// The positions are stored in `Positions.toml`. They belong to a single Zyklop and are not
+/// versioned with git.
+#[derive(Debug, Deserialize, Serialize, Default, PartialEq)]
+pub struct Positions {
+    #[serde(flatten)]
+    pub playlists: BTreeMap<String, Position>,
+}
+
+impl Positions {
+    /// Collect the positions of playlists which have one
+    pub fn from_playlists(playlists: &[Playlist]) -> Positions {
+        let playlists = playlists.iter()
+            .filter_map(|pl| {
+                let (track, seconds) = pl.position?;
+                let file = pl.files.get(track)?.file_name()?.to_string_lossy().into_owned();
+
+                Some((pl.name.clone(), Position { file, track, seconds }))
+            })
+            .collect();
+
+        Positions { playlists }
+    }
+
+    /// Restore the position of a playlist, validated against its current files
+    ///
+    /// The track is searched by its file name first. If the file was renamed or removed, the
+    /// index is clamped to the playlist and the track starts from the beginning.
+    pub fn restore(&self, pl: &mut Playlist) {
+        pl.position = self.playlists.get(&pl.name)
+            .filter(|_| !pl.files.is_empty())
+            .map(|pos| {
+                let found = pl.files.iter()
+                    .position(|x| x.file_name().map(|x| x.to_string_lossy() == pos.file).unwrap_or(false));
+
+                match found {
+                    Some(track) => (track, pos.seconds),
+                    None => (pos.track.min(pl.files.len() - 1), 0),
+                }
+            });
+    }
+
+    /// Load the positions from `/Positions.toml`
+    ///
+    /// Positions are only a convenience, so a missing or unreadable file, like one written by an
+    /// older version, results in no positions at all.
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Positions {
+        fs::read_to_string(path.as_ref().join("Positions.toml")).ok()
+            .and_then(|x| toml::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    /// Write the positions to `/Positions.toml`
+    ///
+    /// The file is replaced atomically and synced, so that a power cut leaves either the old or
+    /// the new positions behind.
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        let tmp = path.as_ref().join(".Positions.toml.tmp");
+
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(toml::to_string(self)?.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(tmp, path.as_ref().join("Positions.toml"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn playlist(files: &[&str]) -> Playlist {
+        Playlist {
+            files: files.iter().map(|x| PathBuf::from("/files/book").join(x)).collect(),
+            ..Playlist::new("book")
+        }
+    }
+
+    fn positions(file: &str, track: usize, seconds: usize) -> Positions {
+        let mut positions = Positions::default();
+        positions.playlists.insert("book".into(), Position { file: file.into(), track, seconds });
+
+        positions
+    }
+
+    #[test]
+    fn restore_by_file_name() {
+        // the track moved after the playlist was reordered
+        let mut pl = playlist(&["1.mp3", "2.mp3", "3.mp3"]);
+        positions("3.mp3", 0, 42).restore(&mut pl);
+        assert_eq!(pl.position, Some((2, 42)));
+    }
+
+    #[test]
+    fn restore_missing_file() {
+        // the file is gone, so the track starts from the beginning
+        let mut pl = playlist(&["1.mp3", "2.mp3", "3.mp3"]);
+        positions("4.mp3", 1, 42).restore(&mut pl);
+        assert_eq!(pl.position, Some((1, 0)));
+
+        // the index is clamped to a playlist which got shorter
+        positions("9.mp3", 8, 42).restore(&mut pl);
+        assert_eq!(pl.position, Some((2, 0)));
+
+        let mut pl = playlist(&[]);
+        positions("1.mp3", 0, 42).restore(&mut pl);
+        assert_eq!(pl.position, None);
+
+        let mut pl = Playlist { name: "other".into(), ..playlist(&["1.mp3"]) };
+        positions("1.mp3", 0, 42).restore(&mut pl);
+        assert_eq!(pl.position, None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = crate::tests::test_dir("positions");
+        let mut pl = playlist(&["1.mp3", "2.mp3"]);
+        pl.position = Some((1, 42));
+
+        let positions = Positions::from_playlists(&[pl, playlist(&["1.mp3"])]);
+        assert_eq!(positions, self::positions("2.mp3", 1, 42));
+
+        positions.save(&dir).unwrap();
+        assert_eq!(Positions::from_path(&dir), positions);
+
+        // an unreadable file is no position at all
+        fs::write(dir.join("Positions.toml"), "[book]\ntrack = \"one\"\n").unwrap();
+        assert_eq!(Positions::from_path(&dir), Positions::default());
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::fs;
+
+use crate::error::{Result, StoreError};
+use crate::{Gains, Manifest, Playlist, Playlists, Positions, Repository, Store};
+
+/// Summary of a synchronisation with a single remote
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Music files downloaded from the remote
+    pub pulled: Vec<String>,
+    /// Music files uploaded to the remote
+    pub pushed: Vec<String>,
+    /// Playlists which got a new card id, because the old one was taken by another playlist
+    pub reassigned: Vec<(String, u32)>,
+    /// Playlists which lost their card UID, because the card is used by another playlist
+    pub unassigned: Vec<String>,
+}
+
+impl Store {
+    /// Exchange the library with another Zyklop
+    ///
+    /// Local changes are committed first, then the remote branch is fetched and merged. Changes to
+    /// `Music.toml` are merged playlist by playlist, with local changes winning when both sides
+    /// modified the same playlist. If both sides assigned the same card to different playlists,
+    /// the remote playlist gets a new card id from `Store::next_card_id`. A card UID can't be
+    /// changed, so the remote playlist loses it instead. Afterwards the music files are exchanged
+    /// and the result is pushed back.
+    pub fn sync(&mut self, remote: &str) -> Result<SyncReport> {
+        let repo = Repository::open(&self.root_path)
+            .ok_or_else(|| StoreError::NoRepository(self.root_path.clone()))?;
+
+        let mut report = SyncReport::default();
+
+        self.save()?;
+
+        repo.fetch(remote)?;
+        let branch = repo.current_branch()?;
+        let theirs = format!("{}/{}", remote, branch);
+
+        let mut diverged = None;
+        if repo.has_revision(&theirs) && !repo.is_ancestor(&theirs, "HEAD") {
+            if repo.is_ancestor("HEAD", &theirs) {
+                repo.fast_forward(&theirs)?;
+                self.playlists = read_playlists(&repo, "HEAD");
+                self.reload()?;
+            } else {
+                diverged = Some(theirs.as_str());
+            }
+        }
+
+        // never leave a half merged workspace behind, until the merge is committed
+        let before = self.playlists.clone();
+        let res = self.merge_and_transfer(&repo, remote, diverged, &mut report);
+        if let Err(err) = res {
+            self.playlists = before;
+            if repo.merge_in_progress() {
+                repo.abort_merge()?;
+            }
+
+            return Err(err);
+        }
+
+        repo.push(remote, &branch)?;
+
+        Ok(report)
+    }
+
+    /// Merge a diverged remote revision, exchange the music files and commit the result
+    fn merge_and_transfer(&mut self, repo: &Repository, remote: &str, theirs: Option<&str>, report: &mut SyncReport) -> Result<()> {
+        if let Some(theirs) = theirs {
+            self.merge(repo, theirs, report)?;
+        }
+
+        // exchange music files of all playlists we know of after the merge
+        let location = Location::from_url(&repo.remote_url(remote)?, &self.root_path);
+        let remote_files = location.list_files()?;
+        let local_files = list_files(&self.root_path.join("files"))?;
+
+        for pl in self.playlists.iter().filter(|x| x.radio_url.is_none()) {
+            let prefix = format!("{}/", pl.name);
+
+            for file in remote_files.iter().filter(|x| x.starts_with(&prefix)) {
+                if !local_files.contains(file) {
+                    location.pull(file, &self.root_path.join("files"))?;
+                    report.pulled.push(file.clone());
+                }
+            }
+
+            for file in local_files.iter().filter(|x| x.starts_with(&prefix)) {
+                if !remote_files.contains(file) {
+                    location.push(&self.root_path.join("files"), file)?;
+                    report.pushed.push(file.clone());
+                }
+            }
+        }
+
+        self.reload()?;
+
+        self.save_with_message(&format!("Merge library from {}", remote))
+    }
+
+    /// Read the files of playlists taken from git, and their positions saved before
+    fn reload(&mut self) -> Result<()> {
+        let positions = Positions::from_path(&self.root_path);
+        for pl in &mut self.playlists {
+            pl.load_files(&self.root_path)?;
+            positions.restore(pl);
+        }
+
+        Ok(())
+    }
+
+    /// Merge a diverged remote revision into the working tree
+    ///
+    /// Playlists whose card had to be changed are added to the report.
+    fn merge(&mut self, repo: &Repository, theirs: &str, report: &mut SyncReport) -> Result<()> {
+        let base = repo.merge_base("HEAD", theirs)
+            .map(|rev| read_playlists(repo, &rev))
+            .unwrap_or_default();
+        let ours = read_playlists(repo, "HEAD");
+        let remote = read_playlists(repo, theirs);
+
+        repo.start_merge(theirs)?;
+
+        // we resolve the store files ourselves, anything else needs a human
+        let conflicts = repo.unmerged_files()?.into_iter()
+            .filter(|x| x != "Music.toml" && x != "Files.toml" && x != "Gains.toml")
+            .collect::<Vec<_>>();
+
+        if !conflicts.is_empty() {
+            return Err(StoreError::MergeConflict(conflicts.join(", ")));
+        }
+
+        self.playlists = merge_playlists(&base, &ours, &remote);
+
+        // the manifest lists files of both sides, until the transfer completes it
+        let mut manifest = read_manifest(repo, "HEAD")?;
+        manifest.extend(read_manifest(repo, theirs)?);
+        manifest.save(&self.root_path)?;
+
+        // both sides may have analyzed files, which the other one receives now
+        if let (Some(mut gains), Some(other)) = (read_gains(repo, "HEAD"), read_gains(repo, theirs)) {
+            gains.extend(other);
+            gains.save(&self.root_path)?;
+        }
+
+        // give remote playlists a new id, if their card is already used by a local playlist
+        for i in 0..self.playlists.len() {
+            let pl = &self.playlists[i];
+            let from_remote = remote.iter().any(|x| x.name == pl.name && x.card_id == pl.card_id)
+                && !ours.iter().any(|x| x.name == pl.name && x.card_id == pl.card_id);
+
+            let taken = pl.card_id.map(|id| self.playlists.iter()
+                .any(|x| x.name != pl.name && x.card_id == Some(id)))
+                .unwrap_or(false);
+
+            if from_remote && taken {
+                let id = self.next_card_id();
+                self.playlists[i].card_id = Some(id);
+                report.reassigned.push((self.playlists[i].name.clone(), id));
+            }
+
+            let pl = &self.playlists[i];
+            let from_remote = remote.iter().any(|x| x.name == pl.name && x.card_uid == pl.card_uid)
+                && !ours.iter().any(|x| x.name == pl.name && x.card_uid == pl.card_uid);
+
+            let taken = pl.card_uid.as_ref().map(|uid| self.playlists.iter()
+                .any(|x| x.name != pl.name && x.card_uid.as_ref() == Some(uid)))
+                .unwrap_or(false);
+
+            if from_remote && taken {
+                self.playlists[i].card_uid = None;
+                report.unassigned.push(self.playlists[i].name.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the playlists of `Music.toml` at a given revision
+fn read_playlists(repo: &Repository, rev: &str) -> Vec<Playlist> {
+    repo.show(rev, "Music.toml")
+        .and_then(|x| Playlists::parse(&x).ok())
+        .map(|x| x.playlists)
+        .unwrap_or_default()
+}
+
+/// Read the manifest `Files.toml` at a given revision
+fn read_manifest(repo: &Repository, rev: &str) -> Result<Manifest> {
+    match repo.show(rev, "Files.toml") {
+        Some(source) => Manifest::parse(&source),
+        None => Ok(Manifest::default()),
+    }
+}
+
+/// Read the loudness cache `Gains.toml` at a given revision
+fn read_gains(repo: &Repository, rev: &str) -> Option<Gains> {
+    repo.show(rev, "Gains.toml").and_then(|x| toml::from_str(&x).ok())
+}
+
+/// Compare the configuration of two playlists, ignoring their runtime state
+fn same_playlist(a: &Playlist, b: &Playlist) -> bool {
+    toml::Value::try_from(a).ok() == toml::Value::try_from(b).ok()
+}
+
+/// Three-way merge of playlists, keyed by their names
+///
+/// A playlist changed on a single side takes this change, a playlist changed on both sides keeps
+/// the local version. Playlists are deleted if one side deleted them and the other did not touch
+/// them.
+fn merge_playlists(base: &[Playlist], ours: &[Playlist], theirs: &[Playlist]) -> Vec<Playlist> {
+    let find = |pls: &[Playlist], name: &str| pls.iter().find(|x| x.name == name).cloned();
+
+    let mut names = ours.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+    for pl in theirs {
+        if !names.contains(&pl.name) {
+            names.push(pl.name.clone());
+        }
+    }
+
+    names.into_iter().filter_map(|name| {
+        let (b, o, t) = (find(base, &name), find(ours, &name), find(theirs, &name));
+
+        match (b, o, t) {
+            (Some(b), Some(o), Some(t)) => if same_playlist(&b, &o) { Some(t) } else { Some(o) },
+            (None, Some(o), Some(_)) => Some(o),
+            (Some(b), Some(o), None) => if same_playlist(&b, &o) { None } else { Some(o) },
+            (Some(b), None, Some(t)) => if same_playlist(&b, &t) { None } else { Some(t) },
+            (None, o, t) => o.or(t),
+            (Some(_), None, None) => None,
+        }
+    }).collect()
+}
+
+/// List all music files in a folder as paths relative to it
+fn list_files(path: &Path) -> Result<BTreeSet<String>> {
+    let mut files = BTreeSet::new();
+    if !path.is_dir() {
+        return Ok(files);
+    }
+
+    for folder in fs::read_dir(path)?.filter_map(|x| x.ok()) {
+        if !folder.path().is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(folder.path())?.filter_map(|x| x.ok()) {
+            if file.path().is_file() {
+                files.insert(format!("{}/{}",
+                    folder.file_name().to_string_lossy(),
+                    file.file_name().to_string_lossy()));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Place where a remote keeps its music files
+///
+/// This is the `files/` folder next to the git repository of the remote. For bare repositories
+/// it is stored inside the repository folder itself.
+enum Location {
+    Local(PathBuf),
+    Ssh { host: String, port: Option<String>, path: String },
+}
+
+impl Location {
+    /// Parse a git remote URL
+    fn from_url(url: &str, root_path: &Path) -> Location {
+        if let Some(path) = url.strip_prefix("file://") {
+            return Location::Local(PathBuf::from(path).join("files"));
+        }
+
+        if let Some(rest) = url.strip_prefix("ssh://") {
+            let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            let (host, port) = match host.rfind(':') {
+                Some(idx) => (&host[..idx], Some(host[idx+1..].to_string())),
+                None => (host, None),
+            };
+
+            return Location::Ssh { host: host.into(), port, path: format!("{}/files", path) };
+        }
+
+        // scp-like syntax `host:path`, but a colon after a slash belongs to a local path
+        match url.find(':') {
+            Some(idx) if !url[..idx].contains('/') => Location::Ssh {
+                host: url[..idx].into(),
+                port: None,
+                path: format!("{}/files", &url[idx+1..]),
+            },
+            _ => Location::Local(root_path.join(url).join("files")),
+        }
+    }
+
+    /// List music files of the remote
+    fn list_files(&self) -> Result<BTreeSet<String>> {
+        match self {
+            Location::Local(path) => list_files(path),
+            Location::Ssh { path, .. } => {
+                // a missing folder is a remote without music
+                let cmd = format!("if cd {} 2>/dev/null; then find . -mindepth 2 -maxdepth 2 -type f; fi", quote(path));
+                let output = self.ssh(&cmd).output().map_err(map_ssh_error)?;
+
+                // otherwise an unreachable remote would look empty and get every file pushed
+                if !output.status.success() {
+                    return Err(StoreError::TransferFailed(path.clone(), String::from_utf8_lossy(&output.stderr).to_string()));
+                }
+
+                Ok(String::from_utf8_lossy(&output.stdout).lines()
+                    .map(|x| x.trim_start_matches("./").to_string())
+                    .collect())
+            }
+        }
+    }
+
+    /// Download a music file into a local folder
+    fn pull(&self, file: &str, local: &Path) -> Result<()> {
+        let target = local.join(file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match self {
+            Location::Local(path) => { fs::copy(path.join(file), target)?; },
+            Location::Ssh { path, .. } => {
+                let output = self.ssh(&format!("cat {}", quote(&format!("{}/{}", path, file))))
+                    .output().map_err(map_ssh_error)?;
+
+                if !output.status.success() {
+                    return Err(StoreError::TransferFailed(file.into(), String::from_utf8_lossy(&output.stderr).to_string()));
+                }
+
+                fs::write(target, output.stdout)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Upload a music file from a local folder
+    fn push(&self, local: &Path, file: &str) -> Result<()> {
+        match self {
+            Location::Local(path) => {
+                let target = path.join(file);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                fs::copy(local.join(file), target)?;
+            },
+            Location::Ssh { path, .. } => {
+                let target = format!("{}/{}", path, file);
+                let folder = target.rsplit_once('/').map(|x| x.0).unwrap_or(".");
+                let cmd = format!("mkdir -p {} && cat > {}", quote(folder), quote(&target));
+
+                let mut child = self.ssh(&cmd)
+                    .stdin(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn().map_err(map_ssh_error)?;
+
+                child.stdin.take().unwrap().write_all(&fs::read(local.join(file))?)?;
+                let output = child.wait_with_output()?;
+
+                if !output.status.success() {
+                    return Err(StoreError::TransferFailed(file.into(), String::from_utf8_lossy(&output.stderr).to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ssh(&self, cmd: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+        if let Location::Ssh { host, port, .. } = self {
+            if let Some(port) = port {
+                ssh.arg("-p").arg(port);
+            }
+
+            ssh.arg(host);
+        }
+
+        ssh.arg(cmd);
+        ssh
+    }
+}
+
+/// Quote a string for a POSIX shell
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn map_ssh_error(err: std::io::Error) -> StoreError {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        StoreError::BinaryMissing("ssh".into())
+    } else {
+        StoreError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_dir;
+
+    /// Create a bare remote and two workspaces sharing it, both with the playlist `a`
+    fn setup(name: &str) -> (PathBuf, Store, Store) {
+        let dir = test_dir(name);
+        let bare = dir.join("remote.git");
+        let git = |args: &[&str]| assert!(Command::new("git").args(args).status().unwrap().success());
+        git(&["init", "--quiet", "--bare", bare.to_str().unwrap()]);
+
+        let mut one = Store::create(dir.join("one")).unwrap();
+        add_playlist(&mut one, "a", &["1.mp3"]);
+        Repository::open(one.root_path()).unwrap()
+            .git(&["remote", "add", "origin", bare.to_str().unwrap()]).unwrap();
+        one.sync("origin").unwrap();
+
+        git(&["clone", "--quiet", bare.to_str().unwrap(), dir.join("two").to_str().unwrap()]);
+        let two = Store::from_path(dir.join("two")).unwrap();
+
+        (bare, one, two)
+    }
+
+    /// Add a playlist with some music files and commit it
+    fn add_playlist(store: &mut Store, name: &str, files: &[&str]) {
+        let folder = store.root_path().join("files").join(name);
+        fs::create_dir_all(&folder).unwrap();
+        for file in files {
+            fs::write(folder.join(file), name).unwrap();
+        }
+
+        store.add_playlists(vec![Playlist::new(name)]).unwrap();
+        store.save().unwrap();
+    }
+
+    /// Store a loudness for a music file and commit the cache
+    fn analyze(store: &Store, name: &str, file: &str) {
+        let mut gains = Gains::from_path(store.root_path());
+        let path = store.root_path().join("files").join(name).join(file);
+        gains.insert(name, &path, crate::Loudness { integrated: -20.0, blocks: 10, peak: 0.5 }).unwrap();
+        gains.save(store.root_path()).unwrap();
+
+        store.save_with_message("Analyze loudness").unwrap();
+    }
+
+    fn names(store: &Store) -> Vec<&str> {
+        store.playlists().iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_diverged_libraries() {
+        let (bare, mut one, mut two) = setup("sync-diverged");
+        assert!(bare.join("files/a/1.mp3").is_file());
+
+        add_playlist(&mut one, "b", &["2.mp3"]);
+        add_playlist(&mut two, "c", &["3.mp3"]);
+        one.sync("origin").unwrap();
+
+        let report = two.sync("origin").unwrap();
+        assert_eq!(names(&two), ["a", "c", "b"]);
+        assert_eq!(report.pulled, ["a/1.mp3", "b/2.mp3"]);
+        assert_eq!(report.pushed, ["c/3.mp3"]);
+        assert_eq!(two.playlists()[2].files.len(), 1);
+
+        // the first one only needs to fast forward
+        let report = one.sync("origin").unwrap();
+        assert_eq!(names(&one), ["a", "c", "b"]);
+        assert_eq!(report.pulled, ["c/3.mp3"]);
+        assert!(!Repository::open(two.root_path()).unwrap().merge_in_progress());
+    }
+
+    #[test]
+    fn reassign_conflicting_card_ids() {
+        let (_, mut one, mut two) = setup("sync-card");
+
+        add_playlist(&mut one, "b", &[]);
+        one.set_playlist_card_id("b", 0).unwrap();
+        one.save().unwrap();
+        add_playlist(&mut two, "c", &[]);
+        two.set_playlist_card_id("c", 0).unwrap();
+        two.save().unwrap();
+        one.sync("origin").unwrap();
+
+        // the local playlist keeps its card, the one from the remote gets the next free id
+        let report = two.sync("origin").unwrap();
+        assert_eq!(report.reassigned, [("b".to_string(), 1)]);
+        assert_eq!(two.playlists().iter().map(|x| x.card_id).collect::<Vec<_>>(), [None, Some(0), Some(1)]);
+        assert!(two.duplicate_card_ids().is_empty());
+    }
+
+    #[test]
+    fn abort_failed_merge() {
+        let (_, mut one, mut two) = setup("sync-abort");
+
+        add_playlist(&mut one, "b", &["2.mp3"]);
+        one.sync("origin").unwrap();
+        add_playlist(&mut two, "c", &[]);
+        let music = fs::read_to_string(two.root_path().join("Music.toml")).unwrap();
+
+        // the music of `b` can't be downloaded, after the merge already started
+        fs::write(two.root_path().join("files/b"), "").unwrap();
+        assert!(two.sync("origin").is_err());
+
+        let repo = Repository::open(two.root_path()).unwrap();
+        assert!(!repo.merge_in_progress());
+        assert_eq!(names(&two), ["a", "c"]);
+        assert_eq!(fs::read_to_string(two.root_path().join("Music.toml")).unwrap(), music);
+
+        // a conflict outside of the store files is left to a human
+        fs::remove_file(two.root_path().join("files/b")).unwrap();
+        fs::write(one.root_path().join("README"), "one").unwrap();
+        Repository::open(one.root_path()).unwrap().commit(&["README"], "Add readme").unwrap();
+        one.sync("origin").unwrap();
+        fs::write(two.root_path().join("README"), "two").unwrap();
+        repo.commit(&["README"], "Add readme").unwrap();
+
+        assert!(matches!(two.sync("origin"), Err(StoreError::MergeConflict(files)) if files == "README"));
+        assert!(!repo.merge_in_progress());
+        assert_eq!(names(&two), ["a", "c"]);
+    }
+
+    #[test]
+    fn keep_state_after_failed_fast_forward() {
+        let (_, mut one, mut two) = setup("sync-fast-forward");
+        two.sync("origin").unwrap();
+        two.set_position("a", 0, 30).unwrap();
+
+        add_playlist(&mut one, "b", &["2.mp3"]);
+        one.sync("origin").unwrap();
+
+        // the library is fast forwarded, before the music of `b` can't be downloaded
+        fs::write(two.root_path().join("files/b"), "").unwrap();
+        assert!(two.sync("origin").is_err());
+        assert_eq!(names(&two), ["a", "b"]);
+        assert_eq!(two.playlists()[0].files.len(), 1);
+        assert_eq!(two.playlists()[0].position, Some((0, 30)));
+
+        let path = two.root_path().to_path_buf();
+        drop(two);
+
+        assert_eq!(Positions::from_path(&path).playlists["a"].seconds, 30);
+        assert_eq!(Manifest::from_path(&path).unwrap().playlists["a"].len(), 1);
+    }
+
+    #[test]
+    fn merge_gains() {
+        let (_, mut one, mut two) = setup("sync-gains");
+
+        add_playlist(&mut one, "b", &["2.mp3"]);
+        analyze(&one, "b", "2.mp3");
+        add_playlist(&mut two, "c", &["3.mp3"]);
+        analyze(&two, "c", "3.mp3");
+        one.sync("origin").unwrap();
+        two.sync("origin").unwrap();
+        one.sync("origin").unwrap();
+
+        for store in [&one, &two] {
+            let gains = Gains::from_path(store.root_path());
+            assert_eq!(gains.playlists.keys().collect::<Vec<_>>(), ["b", "c"]);
+            assert!(gains.track(&store.root_path().join("files/b/2.mp3")).is_some());
+        }
+
+        let repo = Repository::open(one.root_path()).unwrap();
+        assert_eq!(repo.show("HEAD", "Gains.toml"), Some(fs::read_to_string(one.root_path().join("Gains.toml")).unwrap()));
+    }
+}
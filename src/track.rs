@@ -0,0 +1,396 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::fs;
+
+use serde::{Serialize, Deserialize};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+/// Extensions of audio formats which the Zyklop can't decode yet
+const UNSUPPORTED: &[&str] = &["opus"];
+
+/// Audio formats which can be stored in a playlist
+///
+/// Opus is missing on purpose, the Zyklop can't decode it yet. Such files are left out of
+/// playlists and reported by `MediaType::is_unsupported` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Flac,
+    Mp3,
+    Vorbis,
+    M4a,
+    Wav,
+}
+
+impl MediaType {
+    /// Guess the media type from the file extension
+    ///
+    /// Returns `None` for anything which is not music, like cover images or text files.
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Option<MediaType> {
+        let ext = path.as_ref().extension()?.to_str()?.to_lowercase();
+
+        match ext.as_str() {
+            "flac" => Some(MediaType::Flac),
+            "mp3" => Some(MediaType::Mp3),
+            "ogg" | "oga" => Some(MediaType::Vorbis),
+            "m4a" | "mp4" | "aac" => Some(MediaType::M4a),
+            "wav" | "wave" => Some(MediaType::Wav),
+            _ => None,
+        }
+    }
+
+    /// Check whether a path is audio in a format which is left out of playlists, like Opus
+    pub fn is_unsupported<T: AsRef<Path>>(path: T) -> bool {
+        path.as_ref().extension()
+            .and_then(|x| x.to_str())
+            .map(|x| UNSUPPORTED.contains(&x.to_lowercase().as_str()))
+            .unwrap_or(false)
+    }
+
+    /// Check whether a path is a music file, ignoring hidden files
+    pub fn is_music<T: AsRef<Path>>(path: T) -> bool {
+        let path = path.as_ref();
+        let hidden = path.file_name()
+            .map(|x| x.to_string_lossy().starts_with('.'))
+            .unwrap_or(true);
+
+        !hidden && path.is_file() && MediaType::from_path(path).is_some()
+    }
+}
+
+/// A music file with the metadata found in its tags
+///
+/// All metadata is optional, because many files are not tagged properly.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Track {
+    pub path: PathBuf,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration: Option<Duration>,
+}
+
+impl Track {
+    /// Read a track and its metadata from a file
+    ///
+    /// Vorbis comments are read from FLAC files with metaflac. All other formats are probed with
+    /// symphonia, which understands ID3 tags of MP3, Vorbis comments of Ogg, MP4 atoms
+    /// and RIFF info chunks of WAV. If the tags are missing or can't be read, only the path is
+    /// set.
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Track {
+        let path = path.as_ref();
+        let mut track = Track { path: path.to_path_buf(), ..Track::default() };
+
+        match MediaType::from_path(path) {
+            Some(MediaType::Flac) => track.read_flac(),
+            Some(_) => track.read_symphonia(),
+            None => {},
+        }
+
+        track
+    }
+
+    fn read_flac(&mut self) {
+        let tag = match metaflac::Tag::read_from_path(&self.path) {
+            Ok(tag) => tag,
+            Err(_) => return,
+        };
+
+        if let Some(comments) = tag.vorbis_comments() {
+            let first = |key: &str| comments.get(key)
+                .and_then(|x| x.first())
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty());
+
+            self.artist = first("ARTIST");
+            self.album = first("ALBUM");
+            self.title = first("TITLE");
+            self.track_number = first("TRACKNUMBER").and_then(|x| parse_number(&x));
+            self.disc_number = first("DISCNUMBER").and_then(|x| parse_number(&x));
+        }
+
+        if let Some(info) = tag.get_streaminfo() {
+            if info.sample_rate > 0 {
+                let secs = info.total_samples as f64 / info.sample_rate as f64;
+                self.duration = Some(Duration::from_secs_f64(secs));
+            }
+        }
+    }
+
+    fn read_symphonia(&mut self) {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+
+        let mut hint = Hint::new();
+        if let Some(ext) = self.path.extension().and_then(|x| x.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut probed = match symphonia::default::get_probe()
+            .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default()) {
+            Ok(probed) => probed,
+            Err(_) => return,
+        };
+
+        // tags in front of the container (ID3v2) and inside of it are both considered
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|x| x.current()) {
+            self.apply_tags(revision.tags());
+        }
+        if let Some(revision) = probed.format.metadata().current() {
+            self.apply_tags(revision.tags());
+        }
+
+        if let Some(params) = probed.format.default_track().map(|x| &x.codec_params) {
+            if let (Some(frames), Some(rate)) = (params.n_frames, params.sample_rate) {
+                if rate > 0 {
+                    self.duration = Some(Duration::from_secs_f64(frames as f64 / rate as f64));
+                }
+            }
+        }
+    }
+
+    fn apply_tags(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            let value = tag.value.to_string().trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::Artist) => self.artist = Some(value),
+                Some(StandardTagKey::Album) => self.album = Some(value),
+                Some(StandardTagKey::TrackTitle) => self.title = Some(value),
+                Some(StandardTagKey::TrackNumber) => self.track_number = parse_number(&value),
+                Some(StandardTagKey::DiscNumber) => self.disc_number = parse_number(&value),
+                _ => {},
+            }
+        }
+    }
+}
+
+/// Criterion by which the files of a playlist are sorted
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    /// Plain byte-wise order of the file names
+    Filename,
+    /// Order of file names, with numbers compared by value (`2` before `10`)
+    Natural,
+    /// Disc and track number from the tags, falling back to the file name
+    Tag,
+    /// Modification time, oldest first
+    Mtime,
+}
+
+/// Order of the files in a playlist
+///
+/// In `Music.toml` this is either the name of a criterion, like `order = "natural"`, or an
+/// explicit list of file names, like `order = ["intro.flac", "chapter1.flac"]`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Order {
+    Sort(SortBy),
+    /// Files in the list come first in the given order, the rest follows in natural order
+    Explicit(Vec<String>),
+}
+
+impl Default for Order {
+    fn default() -> Order {
+        Order::Sort(SortBy::Tag)
+    }
+}
+
+impl Order {
+    /// Sort tracks in this order
+    ///
+    /// Every criterion falls back to the file name for ties, so that the order is the same on
+    /// every device.
+    pub fn sort(&self, tracks: &mut [Track]) {
+        tracks.sort_by(|a, b| natural_cmp(&file_name(a), &file_name(b)));
+
+        match self {
+            Order::Sort(SortBy::Filename) => tracks.sort_by_key(file_name),
+            Order::Sort(SortBy::Natural) => {},
+            Order::Sort(SortBy::Tag) => tracks.sort_by_key(|x| (x.disc_number.unwrap_or(1), x.track_number)),
+            Order::Sort(SortBy::Mtime) => tracks.sort_by_key(|x| fs::metadata(&x.path).and_then(|x| x.modified()).ok()),
+            Order::Explicit(names) => tracks.sort_by_key(|x| {
+                let name = file_name(x);
+                names.iter().position(|x| x == &name).unwrap_or(names.len())
+            }),
+        }
+    }
+}
+
+fn file_name(track: &Track) -> String {
+    track.path.file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Compare two strings, treating runs of digits as numbers
+///
+/// Strings with the same numbers, like `track 01` and `track 1`, are compared byte-wise, so that
+/// their order does not depend on the order of the directory listing.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    compare_numbers(a, b).then_with(|| a.cmp(b))
+}
+
+/// Compare two strings, with runs of digits compared by their value only
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let (x_trim, y_trim) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+
+                // longer numbers are larger, equal length numbers compare like strings
+                let ord = x_trim.len().cmp(&y_trim.len()).then_with(|| x_trim.cmp(y_trim));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            },
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Consume a run of digits
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        digits.push(*c);
+        chars.next();
+    }
+
+    digits
+}
+
+/// Parse numbers like `3` or `3/12`, as found in track and disc number tags
+fn parse_number(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(name: &str, disc: Option<u32>, number: Option<u32>) -> Track {
+        Track { path: PathBuf::from(name), disc_number: disc, track_number: number, ..Track::default() }
+    }
+
+    fn names(tracks: &[Track]) -> Vec<String> {
+        tracks.iter().map(file_name).collect()
+    }
+
+    #[test]
+    fn media_types() {
+        assert_eq!(MediaType::from_path("a/01.FLAC"), Some(MediaType::Flac));
+        assert_eq!(MediaType::from_path("01.oga"), Some(MediaType::Vorbis));
+        assert_eq!(MediaType::from_path("01.opus"), None);
+        assert!(MediaType::is_unsupported("a/01.Opus"));
+        assert!(!MediaType::is_unsupported("01.ogg"));
+        assert!(!MediaType::is_unsupported("opus"));
+        assert_eq!(MediaType::from_path("cover.jpg"), None);
+        assert_eq!(MediaType::from_path("README"), None);
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number("3"), Some(3));
+        assert_eq!(parse_number(" 3/12"), Some(3));
+        assert_eq!(parse_number("03"), Some(3));
+        assert_eq!(parse_number("/12"), None);
+        assert_eq!(parse_number("A1"), None);
+    }
+
+    #[test]
+    fn sort_by_disc_and_track() {
+        let mut tracks = vec![
+            track("a.flac", Some(2), Some(1)),
+            track("b.flac", Some(1), Some(2)),
+            track("c.flac", None, Some(1)),
+            track("d.flac", Some(2), None),
+            track("e.flac", None, None),
+        ];
+
+        // a missing disc is the first one, missing track numbers come first and by name
+        Order::Sort(SortBy::Tag).sort(&mut tracks);
+        assert_eq!(names(&tracks), ["e.flac", "c.flac", "b.flac", "d.flac", "a.flac"]);
+    }
+
+    #[test]
+    fn compare_naturally() {
+        assert_eq!(natural_cmp("2.mp3", "10.mp3"), Ordering::Less);
+        assert_eq!(natural_cmp("track 9", "track 10"), Ordering::Less);
+        assert_eq!(natural_cmp("track 010", "track 9"), Ordering::Greater);
+        assert_eq!(natural_cmp("track 01", "track 1"), Ordering::Less);
+        assert_eq!(natural_cmp("track 1", "track 01"), Ordering::Greater);
+        assert_eq!(natural_cmp("track 1", "track 1"), Ordering::Equal);
+        assert_eq!(natural_cmp("track 01", "track 2"), Ordering::Less);
+        assert_eq!(natural_cmp("a10b2", "a10b10"), Ordering::Less);
+        assert_eq!(natural_cmp("b", "a10"), Ordering::Greater);
+        assert_eq!(natural_cmp("a", "a1"), Ordering::Less);
+    }
+
+    #[test]
+    fn sort_by_name() {
+        let files = ["10.mp3", "2.mp3", "1.mp3", "B.mp3", "a.mp3"];
+        let mut tracks = files.iter().map(|x| track(x, None, None)).collect::<Vec<_>>();
+
+        Order::Sort(SortBy::Natural).sort(&mut tracks);
+        assert_eq!(names(&tracks), ["1.mp3", "2.mp3", "10.mp3", "B.mp3", "a.mp3"]);
+
+        Order::Sort(SortBy::Filename).sort(&mut tracks);
+        assert_eq!(names(&tracks), ["1.mp3", "10.mp3", "2.mp3", "B.mp3", "a.mp3"]);
+
+        // equal numbers are ordered the same, whatever order the directory lists them in
+        for files in [["1.mp3", "01.mp3", "001.mp3"], ["001.mp3", "1.mp3", "01.mp3"]] {
+            let mut tracks = files.iter().map(|x| track(x, None, None)).collect::<Vec<_>>();
+            Order::Sort(SortBy::Natural).sort(&mut tracks);
+            assert_eq!(names(&tracks), ["001.mp3", "01.mp3", "1.mp3"]);
+        }
+    }
+
+    #[test]
+    fn sort_by_explicit_list() {
+        let files = ["chapter10.mp3", "chapter2.mp3", "outro.mp3", "intro.mp3"];
+        let mut tracks = files.iter().map(|x| track(x, None, None)).collect::<Vec<_>>();
+
+        // unlisted files follow in natural order, unknown names are ignored
+        let order = Order::Explicit(vec!["intro.mp3".into(), "missing.mp3".into(), "outro.mp3".into()]);
+        order.sort(&mut tracks);
+        assert_eq!(names(&tracks), ["intro.mp3", "outro.mp3", "chapter2.mp3", "chapter10.mp3"]);
+    }
+
+    #[test]
+    fn parse_order() {
+        let parse = |source: &str| toml::from_str::<crate::Playlist>(&format!("name = \"a\"\n{}", source)).map(|x| x.order);
+
+        assert_eq!(parse("").unwrap(), Order::Sort(SortBy::Tag));
+        assert_eq!(parse("order = \"natural\"").unwrap(), Order::Sort(SortBy::Natural));
+        assert_eq!(parse("order = [\"b.mp3\", \"a.mp3\"]").unwrap(), Order::Explicit(vec!["b.mp3".into(), "a.mp3".into()]));
+        assert!(parse("order = \"random\"").is_err());
+    }
+}
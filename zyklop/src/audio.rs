@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use hex2::Result;
+
+/// Something which happened during playback, without anybody pressing a button
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent {
+    /// Playback continued with the track at this position
+    TrackChanged(usize),
+    /// The last track of the list finished
+    Finished,
+}
+
+/// Handle to a running playback
+///
+/// Dropping the handle stops the playback.
+pub trait Player: Send {
+    fn next(&mut self) -> Result<()>;
+    fn prev(&mut self) -> Result<()>;
+
+    /// Jump forward or, if negative, backward within the current track
+    fn seek(&mut self, seconds: i64) -> Result<()>;
+
+    /// Halt the playback, keeping the position within the track
+    fn pause(&mut self) -> Result<()>;
+    fn resume(&mut self) -> Result<()>;
+
+    /// Set the volume in percent
+    fn set_volume(&mut self, volume: u8) -> Result<()>;
+    fn current_pos(&self) -> usize;
+
+    /// Return the file of the current track, if it is known
+    fn current_file(&self) -> Option<PathBuf>;
+
+    /// Return how far the playback got into the current track
+    fn elapsed(&self) -> Duration;
+
+    /// Return the next playback event, if there is one
+    fn poll_event(&mut self) -> Option<PlayerEvent>;
+}
+
+/// Way of playing music, creating a `Player` for each playback
+pub trait AudioBackend {
+    /// Play a list of files, starting at `position` if given
+    ///
+    /// The position is the index of the first track and the seconds to skip in it.
+    fn from_list(&self, files: &[PathBuf], shuffle: bool, position: Option<(usize, usize)>) -> Result<Box<dyn Player>>;
+
+    /// Play a radio stream
+    fn from_url(&self, url: &str) -> Result<Box<dyn Player>>;
+}
@@ -0,0 +1,481 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::iter;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use anyhow::{Context, Result, anyhow, bail};
+use rppal::gpio::{Gpio, InputPin, Level};
+
+use crate::gesture::Thresholds;
+
+/// System wide location of the hardware profile
+const SYSTEM_CONFIG: &str = "/etc/zyklop/Zyklop.toml";
+
+/// Highest GPIO number available on the header of a Raspberry Pi
+const MAX_PIN: u8 = 27;
+
+/// Hardware profile of a Zyklop, stored in `Zyklop.toml`
+///
+/// Every section is optional, missing values are those of the original Zyklop wiring:
+///
+/// ```toml
+/// [buttons]
+/// pins = [17, 27, 22]   # previous, shuffle, next
+/// pull = "up"
+/// active = "low"
+///
+/// [power]
+/// pin = 26
+/// pull = "down"
+/// active = "high"
+/// command = ["systemctl", "poweroff"]   # switches the Zyklop off
+/// force_quit = 5        # seconds to hold the button, to quit without shutting down
+///
+/// [reader]
+/// spi = "/dev/spidev0.0"
+/// speed = 100000
+/// reset_pin = 25
+/// write_ids = true      # program cards with an id in block 8, false maps their UID instead
+///
+/// [led]
+/// pins = [6, 13, 19]    # red, green, blue
+/// active = "low"
+///
+/// [gestures]            # all in milliseconds
+/// debounce = 30
+/// long_press = 600
+/// double_press = 0      # disabled, every press is reported immediately
+/// hold_repeat = 500
+///
+/// [volume]              # all in percent
+/// max = 80              # safety cap, no playlist or button goes beyond it
+/// default = 50          # for playlists without their own volume
+/// step = 5
+/// normalize = "album"   # loudness normalization with `odysseus analyze`, "track" or "off"
+///
+/// [playback]
+/// grace_period = 10     # seconds to put a removed card back and continue, zero stops at once
+///
+/// [timers]
+/// fade = 10             # seconds a sleep timer fades out the playback
+/// idle_shutdown = 0     # minutes without playback until the Zyklop switches off, zero never
+///
+/// [mpd]                 # subset of the MPD protocol, for MPD clients on phones
+/// enabled = true
+/// address = "127.0.0.1:6600"   # the Zyklop only, "0.0.0.0:6600" lets phones in the network connect
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub buttons: Buttons,
+    pub power: Power,
+    pub reader: Reader,
+    pub led: Led,
+    pub gestures: Gestures,
+    pub volume: Volume,
+    pub playback: Playback,
+    pub timers: Timers,
+    pub mpd: Mpd,
+}
+
+/// Internal resistor of an input pin
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pull {
+    Up,
+    Down,
+    None,
+}
+
+/// Level of a pin while a button is pressed or a LED is lit
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Active {
+    Low,
+    High,
+}
+
+impl Active {
+    pub fn level(&self) -> Level {
+        match self {
+            Active::Low => Level::Low,
+            Active::High => Level::High,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Buttons {
+    /// Pins of the previous, shuffle and next button
+    pub pins: Vec<u8>,
+    pub pull: Pull,
+    pub active: Active,
+}
+
+impl Default for Buttons {
+    fn default() -> Buttons {
+        Buttons { pins: vec![17, 27, 22], pull: Pull::Up, active: Active::Low }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Power {
+    pub pin: u8,
+    pub pull: Pull,
+    pub active: Active,
+    /// Program and arguments run to switch the Zyklop off
+    pub command: Vec<String>,
+    /// Seconds the button is held to quit at once, instead of shutting down
+    pub force_quit: u64,
+}
+
+impl Default for Power {
+    fn default() -> Power {
+        Power {
+            pin: 26,
+            pull: Pull::Down,
+            active: Active::High,
+            command: vec!["systemctl".into(), "poweroff".into()],
+            force_quit: 5,
+        }
+    }
+}
+
+impl Power {
+    pub fn force_quit(&self) -> Duration {
+        Duration::from_secs(self.force_quit)
+    }
+}
+
+/// The MFRC522 card reader
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reader {
+    pub spi: PathBuf,
+    /// Clock of the SPI bus in Hz
+    pub speed: u32,
+    /// Pin connected to the reset input of the reader, which is held high
+    pub reset_pin: u8,
+    /// Whether new cards are programmed by writing an id, otherwise their UID is assigned
+    pub write_ids: bool,
+}
+
+impl Default for Reader {
+    fn default() -> Reader {
+        Reader { spi: "/dev/spidev0.0".into(), speed: 100_000, reset_pin: 25, write_ids: true }
+    }
+}
+
+/// The RGB LED ring
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Led {
+    /// Pins of the red, green and blue channel
+    pub pins: Vec<u8>,
+    pub active: Active,
+}
+
+impl Default for Led {
+    fn default() -> Led {
+        Led { pins: vec![6, 13, 19], active: Active::Low }
+    }
+}
+
+/// Timing of button gestures in milliseconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gestures {
+    pub debounce: u64,
+    pub long_press: u64,
+    pub double_press: u64,
+    pub hold_repeat: u64,
+}
+
+impl Default for Gestures {
+    fn default() -> Gestures {
+        Gestures { debounce: 30, long_press: 600, double_press: 0, hold_repeat: 500 }
+    }
+}
+
+impl Gestures {
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            debounce: Duration::from_millis(self.debounce),
+            long_press: Duration::from_millis(self.long_press),
+            double_press: Duration::from_millis(self.double_press),
+            hold_repeat: Duration::from_millis(self.hold_repeat),
+        }
+    }
+}
+
+/// Which loudness the gain of a file is based on
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalize {
+    /// All tracks of a playlist get the same gain, keeping quiet and loud songs apart
+    Album,
+    /// Every track is played equally loud
+    Track,
+    Off,
+}
+
+/// Volume limits in percent
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Volume {
+    pub max: u8,
+    pub default: u8,
+    pub step: u8,
+    pub normalize: Normalize,
+}
+
+impl Default for Volume {
+    fn default() -> Volume {
+        Volume { max: 80, default: 50, step: 5, normalize: Normalize::Album }
+    }
+}
+
+/// Behaviour of the playback
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Playback {
+    /// Seconds a removed card only pauses the playback
+    pub grace_period: u64,
+}
+
+impl Default for Playback {
+    fn default() -> Playback {
+        Playback { grace_period: 10 }
+    }
+}
+
+impl Playback {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+/// Time based behaviour
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timers {
+    /// Seconds the volume fades out, before a sleep timer stops the playback
+    pub fade: u64,
+    /// Minutes in idle until the Zyklop switches off, zero keeps it running
+    pub idle_shutdown: u64,
+}
+
+impl Default for Timers {
+    fn default() -> Timers {
+        Timers { fade: 10, idle_shutdown: 0 }
+    }
+}
+
+impl Timers {
+    pub fn fade(&self) -> Duration {
+        Duration::from_secs(self.fade)
+    }
+
+    /// Return how long the Zyklop may stay idle, if it switches off at all
+    pub fn idle_shutdown(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.idle_shutdown * 60)).filter(|x| !x.is_zero())
+    }
+}
+
+/// Server for MPD clients
+///
+/// The protocol has no authentication, so the server only listens on the loopback interface
+/// unless another address is configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mpd {
+    pub enabled: bool,
+    pub address: SocketAddr,
+}
+
+impl Default for Mpd {
+    fn default() -> Mpd {
+        Mpd { enabled: true, address: SocketAddr::from(([127, 0, 0, 1], 6600)) }
+    }
+}
+
+impl Config {
+    /// Load the hardware profile
+    ///
+    /// `Zyklop.toml` is searched next to `Music.toml` first, then in `/etc/zyklop/`. Without
+    /// either, the defaults are used.
+    pub fn load<T: AsRef<Path>>(music_path: T) -> Result<Config> {
+        let candidates = [music_path.as_ref().join("Zyklop.toml"), PathBuf::from(SYSTEM_CONFIG)];
+
+        match candidates.iter().find(|x| x.exists()) {
+            Some(path) => Config::from_path(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Config> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+
+        Config::parse(&source)
+            .with_context(|| format!("invalid hardware profile {}", path.display()))
+    }
+
+    pub fn parse(source: &str) -> Result<Config> {
+        let config: Config = toml::from_str(source)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check that the pins exist and that no pin is used twice
+    fn validate(&self) -> Result<()> {
+        if self.buttons.pins.len() != 3 {
+            bail!("expected three button pins, found {}", self.buttons.pins.len());
+        }
+        if self.led.pins.len() != 3 {
+            bail!("expected three LED pins for red, green and blue, found {}", self.led.pins.len());
+        }
+        if self.reader.speed == 0 {
+            bail!("SPI speed of the reader must not be zero");
+        }
+        if self.gestures.debounce >= self.gestures.long_press {
+            bail!("debounce time must be shorter than a long press");
+        }
+        if self.gestures.hold_repeat == 0 {
+            bail!("hold repeat interval must not be zero");
+        }
+        if self.volume.max > 100 {
+            bail!("maximum volume {} is above 100 percent", self.volume.max);
+        }
+        if self.volume.default > self.volume.max {
+            bail!("default volume {} is above the maximum volume {}", self.volume.default, self.volume.max);
+        }
+        if self.volume.step == 0 {
+            bail!("volume step must not be zero");
+        }
+        if self.power.command.is_empty() {
+            bail!("power off command must not be empty");
+        }
+        if self.power.force_quit == 0 {
+            bail!("force quit time must not be zero");
+        }
+
+        let pins = self.buttons.pins.iter()
+            .chain(self.led.pins.iter())
+            .chain(iter::once(&self.power.pin))
+            .chain(iter::once(&self.reader.reset_pin));
+
+        let mut used = BTreeSet::new();
+        for pin in pins {
+            if *pin > MAX_PIN {
+                bail!("there is no GPIO pin {}, the highest is {}", pin, MAX_PIN);
+            }
+            if !used.insert(pin) {
+                bail!("pin {} is used twice", pin);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Open an input pin with the configured resistor
+pub fn input_pin(gpio: &Gpio, pin: u8, pull: Pull) -> Result<InputPin> {
+    let pin = gpio.get(pin).map_err(|err| anyhow!("could not open pin {}: {}", pin, err))?;
+
+    Ok(match pull {
+        Pull::Up => pin.into_input_pullup(),
+        Pull::Down => pin.into_input_pulldown(),
+        Pull::None => pin.into_input(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> String {
+        format!("{:#}", Config::parse(source).unwrap_err())
+    }
+
+    #[test]
+    fn empty_file_is_default() {
+        let config = Config::parse("").unwrap();
+
+        assert_eq!(config.buttons.pins, [17, 27, 22]);
+        assert_eq!(config.led.pins, [6, 13, 19]);
+        assert_eq!((config.power.pin, config.reader.reset_pin), (26, 25));
+        assert!(config.reader.write_ids);
+        assert_eq!((config.volume.max, config.volume.default), (80, 50));
+        assert_eq!(config.volume.normalize, Normalize::Album);
+        assert_eq!(config.playback.grace_period(), Duration::from_secs(10));
+        assert_eq!(config.timers.idle_shutdown(), None);
+        assert_eq!(config.mpd.address, SocketAddr::from(([127, 0, 0, 1], 6600)));
+    }
+
+    #[test]
+    fn partial_sections() {
+        let config = Config::parse("[volume]\nmax = 60\n\n[timers]\nidle_shutdown = 15\n").unwrap();
+
+        assert_eq!((config.volume.max, config.volume.default, config.volume.step), (60, 50, 5));
+        assert_eq!(config.timers.idle_shutdown(), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(config.timers.fade(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn reject_unknown_fields() {
+        assert!(error("[buttons]\npin = [1, 2, 3]\n").contains("unknown field"));
+        assert!(error("[screen]\nwidth = 320\n").contains("unknown field"));
+        assert!(error("[volume]\nnormalize = \"loud\"\n").contains("unknown variant"));
+    }
+
+    #[test]
+    fn reject_pins() {
+        assert_eq!(error("[led]\npins = [6, 13, 17]\n"), "pin 17 is used twice");
+        assert_eq!(error("[power]\npin = 25\n"), "pin 25 is used twice");
+        assert_eq!(error("[reader]\nreset_pin = 28\n"), "there is no GPIO pin 28, the highest is 27");
+        assert!(Config::parse("[reader]\nreset_pin = 5\n").is_ok());
+    }
+
+    #[test]
+    fn reject_pin_counts() {
+        assert_eq!(error("[buttons]\npins = [17, 27]\n"), "expected three button pins, found 2");
+        assert_eq!(error("[led]\npins = [6, 13, 19, 5]\n"), "expected three LED pins for red, green and blue, found 4");
+    }
+
+    #[test]
+    fn reject_volumes() {
+        assert_eq!(error("[volume]\ndefault = 90\n"), "default volume 90 is above the maximum volume 80");
+        assert_eq!(error("[volume]\nmax = 120\ndefault = 50\n"), "maximum volume 120 is above 100 percent");
+        assert_eq!(error("[volume]\nstep = 0\n"), "volume step must not be zero");
+    }
+
+    #[test]
+    fn reject_timings() {
+        assert_eq!(error("[gestures]\ndebounce = 600\n"), "debounce time must be shorter than a long press");
+        assert_eq!(error("[power]\ncommand = []\n"), "power off command must not be empty");
+        assert_eq!(error("[power]\nforce_quit = 0\n"), "force quit time must not be zero");
+    }
+
+    #[test]
+    fn load_from_music_path() {
+        let path = std::env::temp_dir().join(format!("zyklop-config-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+
+        fs::write(path.join("Zyklop.toml"), "[volume]\nmax = 70\n").unwrap();
+        assert_eq!(Config::load(&path).unwrap().volume.max, 70);
+
+        fs::write(path.join("Zyklop.toml"), "[volume]\nmax = 101\n").unwrap();
+        let err = format!("{:#}", Config::load(&path).unwrap_err());
+        assert!(err.starts_with("invalid hardware profile"), "{}", err);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
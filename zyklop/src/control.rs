@@ -0,0 +1,115 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+
+use anyhow::{Context, Result, bail};
+use hex2::{Request, Response};
+
+/// A request of a client together with the channel for its response
+pub type Call = (Request, Sender<Response>);
+
+/// Hand a request to the main loop and wait for its response
+///
+/// Returns `None` once the main loop is gone, because the Zyklop shuts down.
+pub fn call(calls: &Sender<Call>, request: Request) -> Option<Response> {
+    let (sender, response) = channel();
+    calls.send((request, sender)).ok()?;
+
+    response.recv().ok()
+}
+
+/// Return whether a Zyklop answers on the control socket
+///
+/// A socket left behind by a crashed run still exists, but refuses connections.
+pub fn is_running(path: &Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+/// Listen for clients of the control API on a Unix domain socket
+///
+/// Every client gets its own thread, which forwards the requests to `calls` and waits for the
+/// main loop to answer them. A socket left behind by an earlier run is replaced, but not the one
+/// of a Zyklop still running.
+pub fn spawn_control_thread(path: &Path, calls: Sender<Call>) -> Result<()> {
+    if is_running(path) {
+        bail!("another Zyklop listens on control socket {}", path.display());
+    }
+
+    if path.exists() {
+        fs::remove_file(path)
+            .with_context(|| format!("could not remove old control socket {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("could not listen on control socket {}", path.display()))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let calls = calls.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = serve(stream, calls) {
+                            eprintln!("control client failed: {:?}", err);
+                        }
+                    });
+                },
+                Err(err) => eprintln!("could not accept control client: {:?}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answer the requests of a single client, one JSON object per line
+fn serve(stream: UnixStream, calls: Sender<Call>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => match call(&calls, request) {
+                Some(response) => response,
+                None => return Ok(()),
+            },
+            Err(err) => Response::Error { message: format!("invalid request: {}", err) },
+        };
+
+        let mut answer = serde_json::to_string(&response)?;
+        answer.push('\n');
+        writer.write_all(answer.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_stale_socket_only() {
+        let path = std::env::temp_dir().join(format!("zyklop-control-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // the socket file stays behind, like after a crash
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists() && !is_running(&path));
+
+        let (calls, _received) = channel();
+        spawn_control_thread(&path, calls.clone()).unwrap();
+        assert!(is_running(&path));
+        assert!(spawn_control_thread(&path, calls).is_err());
+        assert!(is_running(&path));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
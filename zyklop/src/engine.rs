@@ -0,0 +1,521 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::{Error as DecodeError, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use hex2::{Gains, Result, StoreError};
+
+use crate::audio::{AudioBackend, Player, PlayerEvent};
+use crate::config::Normalize;
+use crate::mplayer::MplayerBackend;
+use crate::output::{Output, OutputKind, Spec};
+
+/// Play music inside of the Zyklop process
+///
+/// Files are decoded with symphonia and played on an `Output`. Radio streams are not supported by
+/// the decoder, they are handed to mplayer instead.
+///
+/// Files analyzed by `odysseus analyze` are normalized to the same loudness.
+pub struct Engine {
+    output: OutputKind,
+    gains: Gains,
+    normalize: Normalize,
+}
+
+impl Engine {
+    pub fn new(output: OutputKind, gains: Gains, normalize: Normalize) -> Engine {
+        Engine { output, gains, normalize }
+    }
+
+    /// Factor applied to the samples of a file, files which were not analyzed are left alone
+    fn factor(&self, file: &Path) -> f32 {
+        let loudness = match self.normalize {
+            Normalize::Album => file.parent()
+                .and_then(|x| x.file_name())
+                .and_then(|x| self.gains.album(&x.to_string_lossy())),
+            Normalize::Track => self.gains.track(file),
+            Normalize::Off => None,
+        };
+
+        loudness.map(|x| x.factor()).unwrap_or(1.0)
+    }
+}
+
+impl AudioBackend for Engine {
+    fn from_list(&self, files: &[PathBuf], shuffle: bool, position: Option<(usize, usize)>) -> Result<Box<dyn Player>> {
+        for song in files {
+            if !song.exists() {
+                let song_name = song.to_str().unwrap().to_string();
+                return Err(StoreError::SongNotFound(song_name));
+            }
+        }
+
+        let mut files = files.to_vec();
+        if shuffle {
+            shuffle_files(&mut files);
+        }
+
+        let factors = files.iter().map(|x| self.factor(x)).collect();
+
+        Ok(Box::new(EnginePlayer::spawn(files, factors, position, self.output.clone())))
+    }
+
+    fn from_url(&self, url: &str) -> Result<Box<dyn Player>> {
+        MplayerBackend.from_url(url)
+    }
+}
+
+/// Commands sent to the decoder thread
+enum Command {
+    Jump(usize),
+    /// Seconds to skip forward, or backward if negative
+    Seek(i64),
+    Pause,
+    Resume,
+    Stop,
+}
+
+/// Playback state shared between decoder thread and player handle
+struct Status {
+    index: usize,
+    /// Playback time of the samples handed to the output so far
+    elapsed: Duration,
+    /// Factor applied to every sample
+    gain: f32,
+}
+
+/// Handle to a playback of the in-process engine
+pub struct EnginePlayer {
+    /// Played files, in the order after shuffling
+    files: Vec<PathBuf>,
+    commands: Sender<Command>,
+    events: Receiver<PlayerEvent>,
+    status: Arc<Mutex<Status>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EnginePlayer {
+    fn spawn(files: Vec<PathBuf>, factors: Vec<f32>, position: Option<(usize, usize)>, output: OutputKind) -> EnginePlayer {
+        let index = position.map(|x| x.0).unwrap_or(0).min(files.len().saturating_sub(1));
+        let start = Duration::from_secs(position.map(|x| x.1).unwrap_or(0) as u64);
+
+        let status = Arc::new(Mutex::new(Status { index, elapsed: Duration::from_secs(0), gain: 1.0 }));
+        let (commands, commands_recv) = channel();
+        let (events_sender, events) = channel();
+
+        let thread_status = status.clone();
+        let thread_files = files.clone();
+        let thread = thread::spawn(move || {
+            if let Err(err) = decode_thread(thread_files, factors, start, output, commands_recv, events_sender, thread_status) {
+                eprintln!("playback stopped: {:?}", err);
+            }
+        });
+
+        EnginePlayer { files, commands, events, status, thread: Some(thread) }
+    }
+
+    fn jump(&mut self, index: usize) -> Result<()> {
+        let mut status = self.status.lock().unwrap();
+        status.index = index;
+        status.elapsed = Duration::from_secs(0);
+        drop(status);
+
+        self.commands.send(Command::Jump(index))
+            .map_err(|_| StoreError::PlaybackFailed("decoder thread stopped".into()))
+    }
+}
+
+impl Player for EnginePlayer {
+    fn next(&mut self) -> Result<()> {
+        self.jump(self.current_pos() + 1)
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        match self.current_pos() {
+            0 => Err(StoreError::ReachedBeginningOfPlaylist),
+            pos => self.jump(pos - 1),
+        }
+    }
+
+    fn seek(&mut self, seconds: i64) -> Result<()> {
+        self.commands.send(Command::Seek(seconds))
+            .map_err(|_| StoreError::PlaybackFailed("decoder thread stopped".into()))
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.commands.send(Command::Pause)
+            .map_err(|_| StoreError::PlaybackFailed("decoder thread stopped".into()))
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.commands.send(Command::Resume)
+            .map_err(|_| StoreError::PlaybackFailed("decoder thread stopped".into()))
+    }
+
+    /// The volume is applied to the samples, the volume of the sound card is left alone
+    fn set_volume(&mut self, volume: u8) -> Result<()> {
+        // loudness is perceived logarithmically, a square comes close enough
+        let volume = volume.min(100) as f32 / 100.0;
+        self.status.lock().unwrap().gain = volume * volume;
+
+        Ok(())
+    }
+
+    fn current_pos(&self) -> usize {
+        self.status.lock().unwrap().index
+    }
+
+    fn current_file(&self) -> Option<PathBuf> {
+        self.files.get(self.current_pos()).cloned()
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.status.lock().unwrap().elapsed
+    }
+
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for EnginePlayer {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("decoder thread panicked");
+            }
+        }
+    }
+}
+
+/// How the playback of a single track ended
+enum TrackEnd {
+    Finished,
+    Jump(usize),
+    Stop,
+}
+
+fn decode_thread(files: Vec<PathBuf>, factors: Vec<f32>, start: Duration, output: OutputKind, commands: Receiver<Command>, events: Sender<PlayerEvent>, status: Arc<Mutex<Status>>) -> Result<()> {
+    let mut output = output.open()?;
+    let mut index = status.lock().unwrap().index;
+    // only the first track is resumed in the middle
+    let mut start = start;
+
+    loop {
+        if index >= files.len() {
+            let _ = events.send(PlayerEvent::Finished);
+
+            // wait until somebody jumps back into the list
+            index = loop {
+                match commands.recv() {
+                    Ok(Command::Jump(new_index)) => break new_index,
+                    Ok(Command::Seek(_)) | Ok(Command::Pause) | Ok(Command::Resume) => {},
+                    Ok(Command::Stop) | Err(_) => return Ok(()),
+                }
+            };
+
+            continue;
+        }
+
+        {
+            let mut status = status.lock().unwrap();
+            status.index = index;
+            status.elapsed = start;
+        }
+
+        let end = play_track(&files[index], factors[index], start, &mut *output, &commands, &status)
+            .unwrap_or_else(|err| {
+                // skip broken files instead of stopping the whole playlist
+                eprintln!("could not play {:?}: {:?}", files[index], err);
+                TrackEnd::Finished
+            });
+
+        start = Duration::from_secs(0);
+
+        match end {
+            TrackEnd::Finished => {
+                index += 1;
+                if index < files.len() {
+                    let _ = events.send(PlayerEvent::TrackChanged(index));
+                }
+            },
+            TrackEnd::Jump(new_index) => {
+                output.clear();
+                index = new_index;
+            },
+            TrackEnd::Stop => return Ok(()),
+        }
+    }
+}
+
+/// Decode a file, starting at `start`, and write it to the output
+///
+/// Samples are multiplied with `factor` for the loudness normalization and the volume.
+fn play_track(path: &Path, factor: f32, start: Duration, output: &mut dyn Output, commands: &Receiver<Command>, status: &Mutex<Status>) -> Result<TrackEnd> {
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|x| x.to_str()) {
+        hint.with_extension(ext);
+    }
+
+    let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|err| StoreError::PlaybackFailed(err.to_string()))?;
+
+    let track = probed.format.default_track()
+        .ok_or_else(|| StoreError::PlaybackFailed("no audio track found".into()))?;
+    let track_id = track.id;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|err| StoreError::PlaybackFailed(err.to_string()))?;
+
+    // packets before this timestamp are decoded, but not played
+    let mut skip_until = 0;
+    if start > Duration::from_secs(0) {
+        match seek_to(&mut *probed.format, &mut *decoder, track_id, start) {
+            Ok(ts) => skip_until = ts,
+            Err(err) => {
+                // not every file is seekable, start from the beginning instead
+                eprintln!("could not resume {:?}: {:?}", path, err);
+                status.lock().unwrap().elapsed = Duration::from_secs(0);
+            }
+        }
+    }
+
+    let mut samples: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        match commands.try_recv() {
+            Ok(Command::Jump(index)) => return Ok(TrackEnd::Jump(index)),
+            Ok(Command::Seek(seconds)) => {
+                let elapsed = status.lock().unwrap().elapsed;
+                let target = if seconds < 0 {
+                    elapsed.saturating_sub(Duration::from_secs(seconds.unsigned_abs()))
+                } else {
+                    elapsed + Duration::from_secs(seconds as u64)
+                };
+
+                match seek_to(&mut *probed.format, &mut *decoder, track_id, target) {
+                    Ok(ts) => {
+                        skip_until = ts;
+                        output.clear();
+                        status.lock().unwrap().elapsed = target;
+                    },
+                    // winding past the end skips to the next track
+                    Err(DecodeError::SeekError(SeekErrorKind::OutOfRange)) => return Ok(TrackEnd::Finished),
+                    Err(err) => eprintln!("could not seek in {:?}: {:?}", path, err),
+                }
+            },
+            // the quarter second buffered by the output is still played
+            Ok(Command::Pause) => loop {
+                match commands.recv() {
+                    Ok(Command::Resume) => break,
+                    Ok(Command::Jump(index)) => return Ok(TrackEnd::Jump(index)),
+                    Ok(Command::Seek(_)) | Ok(Command::Pause) => {},
+                    Ok(Command::Stop) | Err(_) => return Ok(TrackEnd::Stop),
+                }
+            },
+            Ok(Command::Resume) => {},
+            Ok(Command::Stop) | Err(TryRecvError::Disconnected) => return Ok(TrackEnd::Stop),
+            Err(TryRecvError::Empty) => {},
+        }
+
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(_)) | Err(DecodeError::ResetRequired) => return Ok(TrackEnd::Finished),
+            Err(err) => return Err(StoreError::PlaybackFailed(err.to_string())),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // a single corrupted packet should not stop the track
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(err) => return Err(StoreError::PlaybackFailed(err.to_string())),
+        };
+
+        if packet.ts() + packet.dur() <= skip_until {
+            continue;
+        }
+
+        let spec = *decoded.spec();
+        let buffer = samples.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * spec.channels.count() {
+            *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+
+        let gain = factor * status.lock().unwrap().gain;
+        for sample in buffer.samples_mut() {
+            *sample *= gain;
+        }
+
+        let spec = Spec { channels: spec.channels.count() as u16, sample_rate: spec.rate };
+        output.write(buffer.samples(), spec)?;
+
+        status.lock().unwrap().elapsed += spec.duration(buffer.samples().len());
+    }
+}
+
+/// Seek to a time within a track, returning the timestamp from which packets are played
+fn seek_to(format: &mut dyn FormatReader, decoder: &mut dyn Decoder, track_id: u32, time: Duration) -> symphonia::core::errors::Result<u64> {
+    let seeked = format.seek(SeekMode::Accurate, SeekTo::Time { time: time.into(), track_id: Some(track_id) })?;
+    decoder.reset();
+
+    Ok(seeked.required_ts)
+}
+
+/// Shuffle files in place
+///
+/// This is a Fisher-Yates shuffle with a xorshift generator seeded by the clock, which is random
+/// enough for a playlist.
+fn shuffle_files(files: &mut [PathBuf]) {
+    let mut state = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos() as u64)
+        .unwrap_or(1) | 1;
+
+    for i in (1..files.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        files.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::process;
+
+    const RATE: u32 = 48000;
+
+    /// Write a mono WAV file with every sample set to `value`
+    pub fn write_wav(path: &Path, value: i16, seconds: f32) {
+        let samples = (RATE as f32 * seconds) as usize;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend(&(36 + samples as u32 * 2).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(&16u32.to_le_bytes());
+        wav.extend(&1u16.to_le_bytes());
+        wav.extend(&1u16.to_le_bytes());
+        wav.extend(&RATE.to_le_bytes());
+        wav.extend(&(RATE * 2).to_le_bytes());
+        wav.extend(&2u16.to_le_bytes());
+        wav.extend(&16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(&(samples as u32 * 2).to_le_bytes());
+        for _ in 0..samples {
+            wav.extend(&value.to_le_bytes());
+        }
+
+        std::fs::write(path, wav).unwrap();
+    }
+
+    /// Read a number of samples written by the file output
+    fn read(fifo: &mut File, samples: usize) -> Vec<f32> {
+        let mut bytes = vec![0; samples * 4];
+        fifo.read_exact(&mut bytes).unwrap();
+
+        bytes.chunks(4).map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]])).collect()
+    }
+
+    /// Read until the samples change to another value and return it
+    fn read_until_changed(fifo: &mut File, value: f32) -> f32 {
+        for _ in 0..RATE * 3 / 480 {
+            if let Some(x) = read(fifo, 480).into_iter().find(|x| *x != value) {
+                return x;
+            }
+        }
+
+        panic!("samples never changed from {}", value);
+    }
+
+    #[test]
+    fn play_into_file() {
+        let dir = std::env::temp_dir().join(format!("zyklop-engine-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // every file has a different level, the output tells which one is playing
+        let files = ["a.wav", "b.wav", "c.wav"].iter().map(|x| dir.join(x)).collect::<Vec<_>>();
+        for (file, value) in files.iter().zip([8192, 16384, 24576]) {
+            write_wav(file, value, 3.0);
+        }
+
+        // the decoder blocks on the fifo, so it only advances as far as we read
+        let fifo = dir.join("output");
+        assert!(process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+        let mut player = EnginePlayer::spawn(files, vec![1.0; 3], None, OutputKind::File(fifo.clone()));
+        let mut output = File::open(&fifo).unwrap();
+
+        assert!(read(&mut output, 4800).iter().all(|x| *x == 0.25));
+        player.next().unwrap();
+        assert_eq!(read_until_changed(&mut output, 0.25), 0.5);
+        assert_eq!(player.current_pos(), 1);
+
+        player.prev().unwrap();
+        assert_eq!(read_until_changed(&mut output, 0.5), 0.25);
+        assert_eq!(player.current_pos(), 0);
+        assert!(matches!(player.prev(), Err(StoreError::ReachedBeginningOfPlaylist)));
+        assert_eq!(player.current_pos(), 0);
+
+        // seeking skips a second, much more than we read in the meantime
+        let elapsed = player.elapsed();
+        player.seek(1).unwrap();
+        let mut read_samples = 0;
+        while player.elapsed() < elapsed + Duration::from_secs(1) {
+            read(&mut output, 480);
+            read_samples += 480;
+            assert!(read_samples < RATE as usize / 2, "seek did not skip ahead");
+        }
+
+        // the rest plays to the end of the playlist
+        let rest = thread::spawn(move || {
+            let mut samples = Vec::new();
+            output.read_to_end(&mut samples).unwrap();
+            samples
+        });
+
+        let mut events = Vec::new();
+        for _ in 0..200 {
+            match player.poll_event() {
+                Some(event) => {
+                    events.push(event);
+                    if events.last() == Some(&PlayerEvent::Finished) {
+                        break;
+                    }
+                },
+                None => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        assert_eq!(events, [PlayerEvent::TrackChanged(1), PlayerEvent::TrackChanged(2), PlayerEvent::Finished]);
+        assert_eq!(player.current_pos(), 2);
+
+        drop(player);
+        let rest = rest.join().unwrap();
+        assert_eq!(rest.len() % 4, 0);
+        assert_eq!(f32::from_le_bytes([rest[rest.len() - 4], rest[rest.len() - 3], rest[rest.len() - 2], rest[rest.len() - 1]]), 0.75);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,155 +1,341 @@
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
 
 use spidev::{Spidev, SpidevOptions};
-use rppal::gpio::{Gpio, Level};
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin};
 
-use mfrc522::{MFRC522, pcd::Reg, picc::UID};
+use mfrc522::{MFRC522, picc::UID};
 
-use std::thread;
-use std::time::Duration;
-use anyhow::Result;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, anyhow};
 
-const BUTTON_PINS: &[u64] = &[17, 27, 22];
+use crate::config::{self, Config};
+use crate::gesture::{Gesture, GestureRecognizer};
+use crate::ndef;
+use crate::payload;
 
-#[derive(Debug)]
+/// Highest page of the user memory read from a tag, the end of a NTAG215
+const MAX_PAGE: u8 = 129;
+
+/// Families of cards the reader understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CardKind {
+    /// MIFARE Classic, with 16 byte blocks
+    Classic,
+    /// MIFARE Ultralight and NTAG21x stickers, with 4 byte pages
+    Ultralight,
+}
+
+/// What a card tells about its playlist
+#[derive(Debug, Clone, PartialEq)]
+pub enum CardIdentity {
+    /// Id in block 8 of a MIFARE Classic card, see `payload`
+    Id(u32),
+    /// Playlist named in a NDEF URI record `odysseus://playlist/<name>`, readable by phones
+    Playlist(String),
+    /// Nothing readable, the card is known by its UID only
+    Unknown,
+}
+
+/// A card put on the reader
+#[derive(Debug, Clone, PartialEq)]
+pub struct Card {
+    /// UID in hex
+    pub uid: String,
+    pub kind: CardKind,
+    pub identity: CardIdentity,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
-    ButtonPressed(u8),
-    ThreeButtonPressed,
+    Gesture(Gesture),
     PowerButton(bool),
-    NewCard(u32),
-    CardLost
+    NewCard(Card),
+    CardLost,
+    /// Writing an identity to the card failed, the card keeps its old content
+    WriteFailed(String),
+}
+
+/// Something producing input events, like the buttons and the card reader of a Zyklop
+pub trait InputSource: Send {
+    /// Check the inputs once, returns `None` if nothing happened
+    ///
+    /// Implementations may block for a short while, to avoid busy polling.
+    fn poll(&mut self) -> Result<Option<Event>>;
+
+    /// Write an identity to the card currently on the reader
+    fn write_card(&mut self, identity: CardIdentity) -> Result<()>;
+
+    /// Return true if no more events will follow
+    fn is_finished(&self) -> bool {
+        false
+    }
 }
 
-pub fn spawn_events_thread() -> (Receiver<Event>, Sender<u32>) {
+/// Poll an input source in its own thread
+///
+/// Events are sent to the returned receiver, identities sent to the returned sender are written
+/// to the card on the reader, a failed write is reported as `Event::WriteFailed`. The thread
+/// stops once the source is finished or polling it fails.
+pub fn spawn_events_thread(mut source: Box<dyn InputSource>) -> (Receiver<Event>, Sender<CardIdentity>, JoinHandle<Result<()>>) {
     let (sender, recv) = channel();
     let (sender2, recv2) = channel();
 
-    thread::spawn(|| events_fn(sender, recv2));
+    let handle = thread::spawn(move || {
+        loop {
+            match recv2.try_recv() {
+                Ok(identity) => if let Err(err) = source.write_card(identity) {
+                    if sender.send(Event::WriteFailed(format!("{:#}", err))).is_err() {
+                        return Ok(());
+                    }
+                },
+                Err(TryRecvError::Empty) => {},
+                // main loop is gone, nobody listens to us anymore
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+
+            if source.is_finished() {
+                return Ok(());
+            }
+
+            if let Some(event) = source.poll()? {
+                if sender.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    });
+
+    (recv, sender2, handle)
+}
+
+/// Buttons on the GPIO pins and a MFRC522 card reader on the SPI bus of a Raspberry Pi
+pub struct HardwareInput {
+    inputs: Vec<InputPin>,
+    _reader_reset: OutputPin,
+    mfrc522: MFRC522<'static>,
+    /// Kind of the card on the reader
+    card: Option<CardKind>,
+    /// Whether the power button was pressed during the last poll
+    power: bool,
+    /// Levels of the three buttons and the power button while they are pressed
+    active: Vec<Level>,
+    gestures: GestureRecognizer,
+    /// Gestures recognized, but not returned yet
+    pending: VecDeque<Event>,
+}
+
+impl HardwareInput {
+    pub fn open(config: &Config) -> Result<HardwareInput> {
+        let gpio = Gpio::new()?;
+
+        let mut inputs = config.buttons.pins.iter()
+            .map(|pin| config::input_pin(&gpio, *pin, config.buttons.pull))
+            .collect::<Result<Vec<_>>>()?;
+
+        inputs.push(config::input_pin(&gpio, config.power.pin, config.power.pull)?);
 
-    (recv, sender2)
+        let mut active = vec![config.buttons.active.level(); 3];
+        active.push(config.power.active.level());
+
+        let mut pin = gpio.get(config.reader.reset_pin)?.into_output();
+        pin.set_high();
+
+        let mut spi = Spidev::open(&config.reader.spi)
+            .with_context(|| format!("could not open SPI device {}", config.reader.spi.display()))?;
+        let options = SpidevOptions::new()
+            .lsb_first(false)
+            .bits_per_word(8)
+            .max_speed_hz(config.reader.speed)
+            .mode(spidev::SPI_MODE_0)
+            .build();
+
+        spi.configure(&options)?;
+
+        // the reader borrows the bus for its whole life, which is the life of the Zyklop
+        let spi = Box::leak(Box::new(spi));
+        let mfrc522 = MFRC522::init(spi)
+            .map_err(|err| anyhow!("MFRC522 initialization failed: {:?}", err))?;
+
+        Ok(HardwareInput {
+            inputs,
+            _reader_reset: pin,
+            mfrc522,
+            card: None,
+            power: false,
+            active,
+            gestures: GestureRecognizer::new(3, config.gestures.thresholds()),
+            pending: VecDeque::new(),
+        })
+    }
 }
 
-fn events_fn(sender: Sender<Event>, recv: Receiver<u32>) -> Result<()> {
-    let gpio = Gpio::new()?;
-
-    let mut inputs: Vec<_> = BUTTON_PINS.iter().map(|pin| {
-        gpio.get(*pin as u8).unwrap().into_input_pullup()
-    }).collect();
-
-    inputs.push(gpio.get(26).unwrap().into_input_pulldown());
-
-    let mut pin = gpio.get(25).unwrap().into_output();
-    pin.set_high();
-
-    let mut spi = Spidev::open("/dev/spidev0.0").unwrap();
-    let options = SpidevOptions::new()
-        .lsb_first(false)
-        .bits_per_word(8)
-        .max_speed_hz(100_000)
-        .mode(spidev::SPI_MODE_0)
-        .build();
-
-    spi.configure(&options).unwrap();
-
-    let mut mfrc522 = MFRC522::init(&mut spi).expect("MFRC522 Initialization failed.");
-
-    let mut card_avail = false;
-    let mut uid = UID::default();
-    let mut prev = vec![Level::High, Level::High, Level::High, Level::High];
-    loop {
-        //let vers = mfrc522.register_read(Reg::Version).expect("Could not read version");
-
-        //println!("VERSION: 0x{:x}", vers);
-        if let Ok(new_id) = recv.try_recv() {
-            if card_avail {
-                let mut buffer = [0u8; 16];
-                buffer[0] = (new_id << 24) as u8;
-                buffer[1] = (new_id << 16) as u8;
-                buffer[2] = (new_id << 8) as u8;
-                buffer[3] = (new_id << 0) as u8;
-
-                if mfrc522.mifare_write(8, &buffer).is_ok() {
-                    println!("Id written ..");
-                } else {
-                    panic!("Could not write ID :(!");
+impl HardwareInput {
+    /// Read the identity of a card, which was just selected
+    fn read_identity(&mut self, kind: CardKind) -> CardIdentity {
+        let mut buffer = [0_u8; 18];
+
+        match kind {
+            CardKind::Classic => {
+                let (read_status, nread) = self.mfrc522.mifare_read(8, &mut buffer);
+                if !read_status.is_ok() || nread < payload::BLOCK_SIZE {
+                    return CardIdentity::Unknown;
+                }
+
+                // blank and foreign cards are unprogrammed, not id zero
+                payload::decode(&buffer[..payload::BLOCK_SIZE])
+                    .map(CardIdentity::Id)
+                    .unwrap_or(CardIdentity::Unknown)
+            },
+            CardKind::Ultralight => {
+                // every read returns four pages, read until the TLV blocks are complete
+                let mut data = Vec::new();
+                let mut page = 4;
+                while page <= MAX_PAGE && ndef::tlv_length(&data).is_none() {
+                    let (read_status, nread) = self.mfrc522.mifare_read(page, &mut buffer);
+                    if !read_status.is_ok() || nread < 16 {
+                        break;
+                    }
+
+                    data.extend_from_slice(&buffer[..16]);
+                    page += 4;
                 }
 
-                // reread the id ..
-                card_avail = false;
+                ndef::decode_uri(&data)
+                    .and_then(|x| ndef::playlist_from_uri(&x))
+                    .map(CardIdentity::Playlist)
+                    .unwrap_or(CardIdentity::Unknown)
+            },
+        }
+    }
+
+    /// Write the user memory of a Ultralight tag page by page, starting at page 4
+    fn write_pages(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > (MAX_PAGE as usize - 3) * 4 {
+            return Err(anyhow!("{} bytes do not fit on a tag", data.len()));
+        }
+
+        for (i, page) in data.chunks(4).enumerate() {
+            // the compatibility write takes 16 bytes, of which the tag stores the first four
+            let mut buffer = [0u8; 16];
+            buffer[..page.len()].copy_from_slice(page);
+
+            if !self.mfrc522.mifare_write(4 + i as u8, &buffer).is_ok() {
+                return Err(anyhow!("could not write page {} of tag", 4 + i));
             }
         }
 
-        if card_avail {
+        Ok(())
+    }
+}
+
+impl InputSource for HardwareInput {
+    fn poll(&mut self) -> Result<Option<Event>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
+        }
+
+        if self.card.is_some() {
             let mut buffer = [0_u8; 18];
-            let (read_status, nread) = mfrc522.mifare_read(4, &mut buffer);
+            let (read_status, nread) = self.mfrc522.mifare_read(4, &mut buffer);
             if !read_status.is_ok() || nread == 0 {
                 println!("Lost: {:?}", read_status);
-                card_avail = false;
-                sender.send(Event::CardLost)?;
+                self.card = None;
 
-                continue;
+                return Ok(Some(Event::CardLost));
             }
-        } else {
-            let new_card = mfrc522.picc_is_new_card_present();
-            //println!("{:?}", new_card);
-
-            if let Some(_) = new_card {
-                uid.clear();
-                println!("New card detected!");
-                //println!("New card detected. ATQA: {:04x}", atqa.bits());
-                let status = mfrc522.picc_select(&mut uid);
-            
-                if status.is_ok() {
-                    let mut buffer = vec![0_u8; 18];
-                    let (read_status, nread) = mfrc522.mifare_read(8, &mut buffer);
-                    if read_status.is_ok() && nread > 0 {
-                        card_avail = true;
-                        let id = ((buffer[0] as u32) << 24) |
-                                 ((buffer[1] as u32) << 16) |
-                                 ((buffer[2] as u32) << 8)  |
-                                 ((buffer[3] as u32) << 0);
-
-                        sender.send(Event::NewCard(id))?;
-
-                        continue;
-                    }
-                }
+        } else if self.mfrc522.picc_is_new_card_present().is_some() {
+            let mut uid = UID::default();
+            println!("New card detected!");
+            let status = self.mfrc522.picc_select(&mut uid);
+
+            if status.is_ok() {
+                // Ultralight and NTAG answer the selection with SAK zero
+                let kind = if uid.sak == 0 { CardKind::Ultralight } else { CardKind::Classic };
+                let uid = hex2::format_uid(&uid.bytes[..uid.size as usize]);
+                let identity = self.read_identity(kind);
 
+                self.card = Some(kind);
+
+                return Ok(Some(Event::NewCard(Card { uid, kind, identity })));
             }
         }
 
-        let vals: Vec<Level> = inputs.iter().map(|dev| dev.read()).collect();
+        let vals: Vec<bool> = self.inputs.iter().zip(&self.active)
+            .map(|(dev, active)| dev.read() == *active)
+            .collect();
 
-        let mut events = Vec::new();
-        if vals[3] == Level::Low && prev[3] == Level::High {
-            sender.send(Event::PowerButton(false))?;
-            prev = vals;
-            continue;
-        }
-        else if vals[3] == Level::High && prev[3] == Level::Low {
-            sender.send(Event::PowerButton(true))?;
-            prev = vals;
-            continue;
+        if vals[3] != self.power {
+            self.power = vals[3];
+            return Ok(Some(Event::PowerButton(vals[3])));
         }
 
-        if vals[0] == Level::Low && prev[0] == Level::High {
-            events.push(Event::ButtonPressed(0));
+        let gestures = self.gestures.update(Instant::now(), &vals[..3]);
+        self.pending.extend(gestures.into_iter().map(Event::Gesture));
+
+        thread::sleep(Duration::from_millis(10));
+
+        Ok(self.pending.pop_front())
+    }
+
+    fn write_card(&mut self, identity: CardIdentity) -> Result<()> {
+        match (self.card, &identity) {
+            (Some(CardKind::Classic), CardIdentity::Id(new_id)) => {
+                if !self.mfrc522.mifare_write(8, &payload::encode(*new_id)).is_ok() {
+                    return Err(anyhow!("could not write id {} to card", new_id));
+                }
+            },
+            (Some(CardKind::Ultralight), CardIdentity::Playlist(name)) => {
+                self.write_pages(&ndef::encode_uri(&ndef::playlist_uri(name)))?;
+            },
+            (None, _) => return Ok(()),
+            (Some(kind), identity) => return Err(anyhow!("can't write {:?} to a {:?} card", identity, kind)),
         }
-        if vals[1] == Level::Low && prev[1] == Level::High {
-            events.push(Event::ButtonPressed(1));
+
+        println!("Id written ..");
+
+        // reread the id ..
+        self.card = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Source which can't write cards and reports a lost card once per poll
+    struct BrokenWriter {
+        polls: usize,
+    }
+
+    impl InputSource for BrokenWriter {
+        fn poll(&mut self) -> Result<Option<Event>> {
+            self.polls += 1;
+            thread::sleep(Duration::from_millis(10));
+
+            Ok(Some(Event::CardLost))
         }
-        if vals[2] == Level::Low && prev[2] == Level::High {
-            events.push(Event::ButtonPressed(2));
+
+        fn write_card(&mut self, _: CardIdentity) -> Result<()> {
+            Err(anyhow!("card left the field"))
         }
-        prev = vals;
 
-        if events.len() == 3 {
-            sender.send(Event::ThreeButtonPressed)?;
-            continue;
-        } else if events.len() > 0 {
-            sender.send(events.pop().unwrap())?;
+        fn is_finished(&self) -> bool {
+            self.polls >= 20
         }
+    }
+
+    #[test]
+    fn failed_write_keeps_polling() {
+        let (events, identities, handle) = spawn_events_thread(Box::new(BrokenWriter { polls: 0 }));
+        identities.send(CardIdentity::Id(1)).unwrap();
 
-        thread::sleep(Duration::from_millis(40));
+        let events = events.iter().collect::<Vec<_>>();
+        assert!(events.contains(&Event::WriteFailed("card left the field".into())));
+        assert_eq!(events.iter().filter(|x| **x == Event::CardLost).count(), 20);
+        assert!(handle.join().unwrap().is_ok());
     }
 }
@@ -0,0 +1,337 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+/// What the user did with the buttons
+#[derive(Debug, Clone, PartialEq)]
+pub enum Gesture {
+    /// Button was pressed and released quickly
+    ShortPress(u8),
+    /// Button is held for longer than the long press threshold
+    LongPress(u8),
+    /// Button was pressed twice in quick succession
+    DoublePress(u8),
+    /// Button is still held after a long press, repeated until it is released
+    Hold(u8, Duration),
+    /// Several buttons were held at the same time, in ascending order
+    Chord(Vec<u8>),
+}
+
+/// Timing of the gestures
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Time a level has to be stable, before it is accepted
+    pub debounce: Duration,
+    /// Time until a press becomes a long press
+    pub long_press: Duration,
+    /// Time a second press may follow the first to form a double press, zero disables double
+    /// presses and reports every press immediately
+    pub double_press: Duration,
+    /// Interval of `Hold` gestures after a long press
+    pub hold_repeat: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Button {
+    /// Last level read, with the time it changed
+    raw: bool,
+    raw_since: Instant,
+    /// Debounced level
+    pressed: bool,
+    pressed_at: Instant,
+    /// Press was already reported as long press or double press
+    reported: bool,
+    /// Press became a long press
+    long: bool,
+    last_hold: Instant,
+    /// Release of a short press, waiting for a second press
+    released_at: Option<Instant>,
+}
+
+/// Turn the levels of buttons into gestures
+///
+/// The recognizer is fed with the pressed state of all buttons in regular intervals and returns
+/// the gestures completed since the last update. Gestures depending on time, like long presses,
+/// are only reported on updates, so the interval should be well below the thresholds.
+pub struct GestureRecognizer {
+    thresholds: Thresholds,
+    buttons: Vec<Button>,
+    /// Buttons pressed together since the first of them went down
+    chord: BTreeSet<u8>,
+}
+
+impl GestureRecognizer {
+    pub fn new(count: usize, thresholds: Thresholds) -> GestureRecognizer {
+        let now = Instant::now();
+        let button = Button {
+            raw: false,
+            raw_since: now,
+            pressed: false,
+            pressed_at: now,
+            reported: false,
+            long: false,
+            last_hold: now,
+            released_at: None,
+        };
+
+        GestureRecognizer { thresholds, buttons: vec![button; count], chord: BTreeSet::new() }
+    }
+
+    /// Feed the current pressed state of every button
+    pub fn update(&mut self, now: Instant, levels: &[bool]) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+
+        for (i, level) in levels.iter().enumerate().take(self.buttons.len()) {
+            let button = &mut self.buttons[i];
+            if *level != button.raw {
+                button.raw = *level;
+                button.raw_since = now;
+            }
+
+            if button.raw != button.pressed && now.duration_since(button.raw_since) >= self.thresholds.debounce {
+                button.pressed = button.raw;
+
+                if button.pressed {
+                    self.press(i, now, &mut gestures);
+                } else {
+                    self.release(i, now, &mut gestures);
+                }
+            }
+        }
+
+        self.tick(now, &mut gestures);
+
+        gestures
+    }
+
+    fn press(&mut self, i: usize, now: Instant, gestures: &mut Vec<Gesture>) {
+        // a press while another button is held, but not long pressed yet, starts a chord
+        let others = self.buttons.iter().enumerate()
+            .any(|(j, x)| j != i && x.pressed && !x.long);
+
+        if others || self.chord.len() > 1 {
+            self.chord.insert(i as u8);
+            for button in &mut self.buttons {
+                button.released_at = None;
+            }
+
+            return;
+        }
+
+        self.flush_short_presses(i, gestures);
+        self.chord = [i as u8].iter().copied().collect();
+
+        let button = &mut self.buttons[i];
+        button.pressed_at = now;
+        button.reported = false;
+        button.long = false;
+
+        if button.released_at.take().is_some() {
+            button.reported = true;
+            gestures.push(Gesture::DoublePress(i as u8));
+        }
+    }
+
+    fn release(&mut self, i: usize, now: Instant, gestures: &mut Vec<Gesture>) {
+        if self.chord.len() > 1 {
+            if self.buttons.iter().all(|x| !x.pressed) {
+                gestures.push(Gesture::Chord(self.chord.iter().copied().collect()));
+                self.chord.clear();
+            }
+
+            return;
+        }
+
+        self.chord.clear();
+
+        let button = &mut self.buttons[i];
+        if button.reported {
+            return;
+        }
+
+        if self.thresholds.double_press == Duration::from_secs(0) {
+            gestures.push(Gesture::ShortPress(i as u8));
+        } else {
+            button.released_at = Some(now);
+        }
+    }
+
+    fn tick(&mut self, now: Instant, gestures: &mut Vec<Gesture>) {
+        let chording = self.chord.len() > 1;
+
+        for (i, button) in self.buttons.iter_mut().enumerate() {
+            if button.pressed && !chording {
+                let held = now.duration_since(button.pressed_at);
+
+                if !button.reported && held >= self.thresholds.long_press {
+                    button.reported = true;
+                    button.long = true;
+                    button.last_hold = now;
+                    gestures.push(Gesture::LongPress(i as u8));
+                } else if button.long && now.duration_since(button.last_hold) >= self.thresholds.hold_repeat {
+                    button.last_hold = now;
+                    gestures.push(Gesture::Hold(i as u8, held));
+                }
+            }
+
+            // no second press followed in time
+            if let Some(released_at) = button.released_at {
+                if now.duration_since(released_at) >= self.thresholds.double_press {
+                    button.released_at = None;
+                    gestures.push(Gesture::ShortPress(i as u8));
+                }
+            }
+        }
+    }
+
+    /// Report short presses waiting for a second press, except for one button
+    fn flush_short_presses(&mut self, except: usize, gestures: &mut Vec<Gesture>) {
+        for (i, button) in self.buttons.iter_mut().enumerate() {
+            if i != except && button.released_at.take().is_some() {
+                gestures.push(Gesture::ShortPress(i as u8));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Recognizer fed with levels at milliseconds since its start
+    struct Buttons {
+        recognizer: GestureRecognizer,
+        start: Instant,
+    }
+
+    impl Buttons {
+        fn new(double_press: u64) -> Buttons {
+            let thresholds = Thresholds {
+                debounce: Duration::from_millis(30),
+                long_press: Duration::from_millis(600),
+                double_press: Duration::from_millis(double_press),
+                hold_repeat: Duration::from_millis(500),
+            };
+            let recognizer = GestureRecognizer::new(3, thresholds);
+
+            Buttons { recognizer, start: Instant::now() }
+        }
+
+        fn at(&mut self, millis: u64, levels: [bool; 3]) -> Vec<Gesture> {
+            self.recognizer.update(self.start + Duration::from_millis(millis), &levels)
+        }
+    }
+
+    const NONE: [bool; 3] = [false, false, false];
+    const FIRST: [bool; 3] = [true, false, false];
+    const SECOND: [bool; 3] = [false, true, false];
+
+    #[test]
+    fn debounce() {
+        let mut buttons = Buttons::new(0);
+
+        // bouncing contacts restart the debounce time
+        assert_eq!(buttons.at(0, FIRST), []);
+        assert_eq!(buttons.at(10, NONE), []);
+        assert_eq!(buttons.at(20, FIRST), []);
+        assert_eq!(buttons.at(45, FIRST), []);
+        assert_eq!(buttons.at(50, FIRST), []);
+
+        // a short glitch while held is no release
+        assert_eq!(buttons.at(100, NONE), []);
+        assert_eq!(buttons.at(110, FIRST), []);
+        assert_eq!(buttons.at(200, NONE), []);
+        assert_eq!(buttons.at(220, NONE), []);
+        assert_eq!(buttons.at(230, NONE), [Gesture::ShortPress(0)]);
+        assert_eq!(buttons.at(1000, NONE), []);
+    }
+
+    #[test]
+    fn short_and_long_press() {
+        let mut buttons = Buttons::new(0);
+
+        buttons.at(0, SECOND);
+        buttons.at(30, SECOND);
+        buttons.at(500, NONE);
+        assert_eq!(buttons.at(530, NONE), [Gesture::ShortPress(1)]);
+
+        // the long press is reported while the button is still held, the release is silent
+        buttons.at(1000, SECOND);
+        buttons.at(1030, SECOND);
+        assert_eq!(buttons.at(1629, SECOND), []);
+        assert_eq!(buttons.at(1630, SECOND), [Gesture::LongPress(1)]);
+        buttons.at(1700, NONE);
+        assert_eq!(buttons.at(1730, NONE), []);
+    }
+
+    #[test]
+    fn hold_repeats() {
+        let mut buttons = Buttons::new(0);
+
+        buttons.at(0, FIRST);
+        buttons.at(30, FIRST);
+        assert_eq!(buttons.at(630, FIRST), [Gesture::LongPress(0)]);
+        assert_eq!(buttons.at(1000, FIRST), []);
+        assert_eq!(buttons.at(1130, FIRST), [Gesture::Hold(0, Duration::from_millis(1100))]);
+        assert_eq!(buttons.at(1500, FIRST), []);
+        assert_eq!(buttons.at(1630, FIRST), [Gesture::Hold(0, Duration::from_millis(1600))]);
+
+        buttons.at(1700, NONE);
+        assert_eq!(buttons.at(1730, NONE), []);
+        assert_eq!(buttons.at(3000, NONE), []);
+    }
+
+    #[test]
+    fn double_press() {
+        let mut buttons = Buttons::new(300);
+
+        buttons.at(0, FIRST);
+        buttons.at(30, FIRST);
+        buttons.at(100, NONE);
+        assert_eq!(buttons.at(130, NONE), []);
+        buttons.at(250, FIRST);
+        assert_eq!(buttons.at(280, FIRST), [Gesture::DoublePress(0)]);
+        buttons.at(350, NONE);
+        assert_eq!(buttons.at(380, NONE), []);
+        assert_eq!(buttons.at(1000, NONE), []);
+
+        // without a second press, the short press is reported once the window closed
+        buttons.at(2000, FIRST);
+        buttons.at(2030, FIRST);
+        buttons.at(2100, NONE);
+        buttons.at(2130, NONE);
+        assert_eq!(buttons.at(2429, NONE), []);
+        assert_eq!(buttons.at(2430, NONE), [Gesture::ShortPress(0)]);
+
+        // pressing another button reports the waiting press at once
+        buttons.at(3000, FIRST);
+        buttons.at(3030, FIRST);
+        buttons.at(3100, NONE);
+        buttons.at(3130, NONE);
+        buttons.at(3200, SECOND);
+        assert_eq!(buttons.at(3230, SECOND), [Gesture::ShortPress(0)]);
+    }
+
+    #[test]
+    fn chord() {
+        let mut buttons = Buttons::new(0);
+
+        buttons.at(0, [true, false, true]);
+        assert_eq!(buttons.at(30, [true, false, true]), []);
+
+        // neither button becomes a long press
+        assert_eq!(buttons.at(1000, [true, false, true]), []);
+        assert_eq!(buttons.at(1050, [false, false, true]), []);
+        assert_eq!(buttons.at(1080, [false, false, true]), []);
+        buttons.at(1200, NONE);
+        assert_eq!(buttons.at(1230, NONE), [Gesture::Chord(vec![0, 2])]);
+
+        // a button pressed during a long press is a press of its own
+        buttons.at(2000, FIRST);
+        buttons.at(2030, FIRST);
+        assert_eq!(buttons.at(2630, FIRST), [Gesture::LongPress(0)]);
+        buttons.at(2700, [true, true, false]);
+        buttons.at(2730, [true, true, false]);
+        buttons.at(2800, FIRST);
+        assert_eq!(buttons.at(2830, FIRST), [Gesture::ShortPress(1)]);
+    }
+}
@@ -3,38 +3,54 @@ use std::thread::{spawn, JoinHandle};
 use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 pub use rppal::gpio::{Result, Gpio, OutputPin};
 
+use crate::config::{self, Active};
+
 type Speed = f32;
-type Pins = (OutputPin, OutputPin, OutputPin);
 
+/// Output pins of the red, green and blue channel
+pub struct Pins {
+    channels: [OutputPin; 3],
+    active: Active,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Color(pub u8, pub u8, pub u8, pub u8);
 
 /// Set pins to specific color
 pub fn set_color(pins: &mut Pins, color: &Color) -> Result<()> {
-    let Color(ref r, ref g, ref b, ref a) = color;
+    let Color(r, g, b, a) = *color;
 
-    let r = 1. - (*r as f64) / 255. * (*a as f64) / 255.;
-    let g = 1. - (*g as f64) / 255. * (*a as f64) / 255.;
-    let b = 1. - (*b as f64) / 255. * (*a as f64) / 255.;
+    for (pin, value) in pins.channels.iter_mut().zip([r, g, b].iter()) {
+        let mut duty = (*value as f64) / 255. * (a as f64) / 255.;
+
+        // a ring lit on low level needs the inverted duty cycle
+        if pins.active == Active::Low {
+            duty = 1. - duty;
+        }
 
-    pins.0.set_pwm_frequency(50., r)?;
-    pins.1.set_pwm_frequency(50., g)?;
-    pins.2.set_pwm_frequency(50., b)?;
+        pin.set_pwm_frequency(50., duty)?;
+    }
 
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
     Sawtooth(Color, Speed),
     Sine(Color, Speed),
     Continuous(Color),
 }
 
-pub fn spawn_led_thread() -> Result<(Sender<State>, JoinHandle<()>)> {
-    let mut pins = (
-        Gpio::new()?.get(6)?.into_output(),
-        Gpio::new()?.get(13)?.into_output(),
-        Gpio::new()?.get(19)?.into_output(),
-    );
+pub fn spawn_led_thread(config: &config::Led) -> Result<(Sender<State>, JoinHandle<()>)> {
+    let gpio = Gpio::new()?;
+    let mut pins = Pins {
+        channels: [
+            gpio.get(config.pins[0])?.into_output(),
+            gpio.get(config.pins[1])?.into_output(),
+            gpio.get(config.pins[2])?.into_output(),
+        ],
+        active: config.active,
+    };
 
     // set color to black, i.e. disable LEDs
     set_color(&mut pins, &Color(0, 0, 0, 0))?;
@@ -87,3 +103,12 @@ pub fn spawn_led_thread() -> Result<(Sender<State>, JoinHandle<()>)> {
 
     Ok((sender, res))
 }
+
+/// Spawn a thread which ignores all states, for devices without LED ring
+pub fn spawn_null_thread() -> (Sender<State>, JoinHandle<()>) {
+    let (sender, receiver) = channel();
+
+    let res = spawn(move || for _ in receiver {});
+
+    (sender, res)
+}
@@ -1,222 +1,583 @@
+mod config;
+mod control;
 mod led;
+mod mpd;
 mod events;
+mod ndef;
+mod gesture;
+mod audio;
+mod engine;
 mod mplayer;
+mod output;
+mod payload;
+mod simulator;
+mod state;
 
-use std::sync::mpsc::{Sender, RecvTimeoutError};
-use std::time::Duration;
-use std::thread;
+use std::sync::mpsc::{channel, Receiver, Sender, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::thread::{self, JoinHandle};
 
 use rppal::system::DeviceInfo;
-use anyhow::{Context, Result, anyhow};
-
-use hex2::{Playlist, StoreError};
+use anyhow::{Context, Result, anyhow, bail};
+
+use config::Config;
+use events::{CardIdentity, Event, HardwareInput, InputSource};
+use audio::{AudioBackend, Player};
+use engine::Engine;
+use mplayer::MplayerBackend;
+use output::OutputKind;
+use simulator::{ScriptInput, StdinInput};
+use state::{Command, Effect, Input, Progress, State, StateMachine};
+use hex2::{Mode, Request, Response};
+
+/// How long the playback fades out, when the power button switches the Zyklop off
+const SHUTDOWN_FADE: Duration = Duration::from_millis(1500);
+
+/// Select the audio backend from `ZYKLOP_AUDIO`
+///
+/// Possible values are `engine` (the default), `mplayer`, `null` and `file:<path>`. The last two
+/// play with the in-process engine, but without a sound card. Mplayer does not normalize the
+/// loudness.
+fn audio_backend(path: &str, config: &Config) -> Result<Box<dyn AudioBackend>> {
+    let name = std::env::var("ZYKLOP_AUDIO").unwrap_or_else(|_| "engine".into());
+    let engine = |output| Engine::new(output, hex2::Gains::from_path(path), config.volume.normalize);
+
+    match name.as_str() {
+        "engine" => Ok(Box::new(engine(OutputKind::Device))),
+        "mplayer" => Ok(Box::new(MplayerBackend)),
+        "null" => Ok(Box::new(engine(OutputKind::Null))),
+        _ => match name.strip_prefix("file:") {
+            Some(file) => Ok(Box::new(engine(OutputKind::File(file.into())))),
+            None => Err(anyhow!("unknown audio backend `{}` in `ZYKLOP_AUDIO`", name)),
+        }
+    }
+}
 
-use events::Event;
-use mplayer::Mplayer;
-use led::Color;
+/// Select the source of input events from `ZYKLOP_INPUT`
+///
+/// Possible values are `hardware` (the default), `stdin` and `script:<path>`. The last two
+/// simulate buttons and cards, so that a Zyklop runs without a Raspberry Pi.
+fn input_source(config: &Config) -> Result<Box<dyn InputSource>> {
+    let name = std::env::var("ZYKLOP_INPUT").unwrap_or_else(|_| "hardware".into());
+
+    match name.as_str() {
+        "hardware" => Ok(Box::new(HardwareInput::open(config)?)),
+        "stdin" => Ok(Box::new(StdinInput::new())),
+        _ => match name.strip_prefix("script:") {
+            Some(path) => Ok(Box::new(ScriptInput::from_path(path)?)),
+            None => Err(anyhow!("unknown input source `{}` in `ZYKLOP_INPUT`", name)),
+        }
+    }
+}
 
-pub enum State {
-    Idle,
-    Playing(Playlist, Mplayer),
-    Programming(u32, Mplayer, Vec<Playlist>),
+/// Executes the effects of the state machine
+struct Driver<'a> {
+    store: hex2::Store,
+    backend: Box<dyn AudioBackend>,
+    player: Option<Box<dyn Player>>,
+    led_state: &'a Sender<led::State>,
+    events_in: Sender<CardIdentity>,
+    /// Volume in percent, applied to every new player
+    volume: u8,
+    /// Set once the state machine decided to power off the Zyklop
+    shutdown: bool,
 }
 
-fn process_error(state: State, led_state: &Sender<led::State>, duration: u32, error: anyhow::Error) -> Result<State> {
-    eprintln!("Got error: {:?}", error);
+impl<'a> Driver<'a> {
+    fn progress(&self) -> Option<Progress> {
+        self.player.as_ref().map(|player| Progress { track: player.current_pos(), elapsed: player.elapsed() })
+    }
 
-    led_state.send(led::State::Sine(led::Color(255, 0, 0, 255), 300.0))?;
+    /// Execute a single effect
+    ///
+    /// Returns false if a player could not be started. The error is shown to the user, but the
+    /// Zyklop continues to run.
+    fn execute(&mut self, effect: Effect) -> Result<bool> {
+        match effect {
+            Effect::Play { files, shuffle, position } => {
+                // stop the old playback first, both might use the same sound card
+                self.player = None;
+
+                match self.backend.from_list(&files, shuffle, position) {
+                    Ok(player) => self.start(player)?,
+                    Err(err) => {
+                        self.show_error(err.into(), Duration::from_millis(1000))?;
+                        return Ok(false);
+                    }
+                }
+            },
+            Effect::PlayUrl(url) => {
+                self.player = None;
+
+                match self.backend.from_url(&url) {
+                    Ok(player) => self.start(player)?,
+                    Err(err) => {
+                        self.show_error(err.into(), Duration::from_millis(1000))?;
+                        return Ok(false);
+                    }
+                }
+            },
+            Effect::Stop => self.player = None,
+            Effect::Pause => if let Some(player) = &mut self.player { player.pause()? },
+            Effect::Resume => if let Some(player) = &mut self.player { player.resume()? },
+            Effect::Next => if let Some(player) = &mut self.player { player.next()? },
+            Effect::Prev => if let Some(player) = &mut self.player { player.prev()? },
+            Effect::Seek(seconds) => if let Some(player) = &mut self.player { player.seek(seconds)? },
+            Effect::Led(state) => self.led_state.send(state)?,
+            Effect::WriteCard(identity) => self.events_in.send(identity)?,
+            Effect::SetPosition { playlist, track, seconds } => {
+                // a toy is usually switched off by pulling the plug, so the position goes to disk right away
+                self.store.set_position(&playlist, track, seconds)?;
+                self.store.save_positions()?;
+            },
+            Effect::AssignCard { playlist, card_id } => {
+                self.store.set_playlist_card_id(&playlist, card_id)?;
+                self.store.save_with_message(&format!("Assign card {} to playlist {}", card_id, playlist))?;
+            },
+            Effect::AssignCardUid { playlist, uid } => {
+                self.store.set_playlist_card_uid(&playlist, &uid)?;
+                self.store.save_with_message(&format!("Assign card {} to playlist {}", uid, playlist))?;
+            },
+            Effect::Error(err, duration) => self.show_error(err.into(), duration)?,
+            Effect::Volume(volume) => {
+                self.volume = volume;
+                if let Some(player) = &mut self.player {
+                    player.set_volume(volume)?;
+                }
+            },
+            Effect::ShowVolume(volume, max) => {
+                // the next LED effect restores the color of the state
+                self.led_state.send(led::State::Continuous(state::volume_color(volume, max)))?;
+                thread::sleep(Duration::from_millis(700));
+            },
+            Effect::Shutdown => self.shutdown = true,
+            Effect::ForceQuit => return Err(anyhow!("power button held, quitting without shutdown")),
+        }
 
-    thread::sleep(Duration::from_millis(duration as u64));
+        Ok(true)
+    }
 
-    match &state {
-        State::Playing(pl, _) => {
-            if pl.radio_url.is_some() {
-                led_state.send(led::State::Continuous(led::Color(0, 255, 255, 255)))?
-            } else {
-                led_state.send(led::State::Continuous(led::Color(0, 0, 255, 255)))?
+    /// Fade out the playback and persist the library, before the Zyklop switches off
+    fn shut_down(&mut self) -> Result<()> {
+        if let Some(player) = &mut self.player {
+            let steps = (SHUTDOWN_FADE.as_millis() / 50) as u32;
+            for step in (0..steps).rev() {
+                player.set_volume((self.volume as u32 * step / steps) as u8)?;
+                thread::sleep(Duration::from_millis(50));
             }
-        },
-        State::Programming(..) => led_state.send(led::State::Continuous(led::Color(255, 255, 0, 255)))?,
-        State::Idle => led_state.send(led::State::Continuous(led::Color(255, 255, 255, 255)))?,
+        }
+        self.player = None;
+
+        Ok(self.store.save()?)
     }
 
-    Ok(state)
-}
+    fn start(&mut self, mut player: Box<dyn Player>) -> Result<()> {
+        player.set_volume(self.volume)?;
+        self.player = Some(player);
 
-fn process(led_state: &Sender<led::State>) -> Result<()> {
-    let device_info = DeviceInfo::new()
-        .with_context(|| "Could not get Raspberry PI device info")?;
+        Ok(())
+    }
 
-    println!("Starting Zyklop on device {}", device_info.model());
+    /// Blink red for a while, the next LED effect restores the color of the state
+    fn show_error(&self, error: anyhow::Error, duration: Duration) -> Result<()> {
+        eprintln!("Got error: {:?}", error);
 
-    // spawn LED ring and events thread
-    let (events_out, events_in) = events::spawn_events_thread();
+        self.led_state.send(led::State::Sine(led::Color(255, 0, 0, 255), 300.0))?;
 
-    // open music storage
-    let path = std::env::var("ZYKLOP_PATH")
-        .map_err(|_| anyhow!("could not find path in `ZYKLOP_PATH`"))?;
+        thread::sleep(duration);
 
-    let mut store = hex2::Store::from_path(&path)?;
-    let mut state = State::Idle;
+        Ok(())
+    }
+}
 
-    'outer: loop {
-        // get a new input event and convert into reference to array
-        let answ = match events_out.recv_timeout(Duration::from_millis(50)) {
-            Ok(new_input) => new_input,
-            Err(RecvTimeoutError::Timeout) => continue 'outer,
-            Err(RecvTimeoutError::Disconnected) => 
-                break 'outer Err(anyhow!("pipe breaked!")),
-        };
+/// Run an input through the state machine and execute its effects
+///
+/// Returns the new state and the first error shown to the user, if there was one.
+fn step(machine: &mut StateMachine, driver: &mut Driver, state: State, input: Input) -> Result<(State, Option<String>)> {
+    let (mut state, effects) = machine.handle(state, input, driver.progress());
+
+    let mut error = effects.iter().find_map(|x| match x {
+        Effect::Error(err, _) => Some(err.to_string()),
+        _ => None,
+    });
+
+    for effect in effects {
+        if !driver.execute(effect)? {
+            // without a player there is nothing to do but to wait for the next card
+            state = State::Idle;
+            driver.player = None;
+            driver.led_state.send(state::led_state(&state))?;
+            error.get_or_insert_with(|| "could not start playback".into());
+            break;
+        }
+    }
 
-        let new_state = match (answ, state) {
-            (Event::NewCard(id), state @ State::Idle) => {
-                let playlist = store.playlists().into_iter()
-                    .filter(|x| x.card_id.map(|x| x == id).unwrap_or(false))
-                    .next();
-
-                if let Some(pl) = playlist {
-                    if pl.radio_url.is_some() {
-                        led_state.send(led::State::Sine(led::Color(0, 255, 255, 255), 500.0))?
-                    } else {
-                        led_state.send(led::State::Sine(led::Color(0, 0, 255, 255), 500.0))?
-                    }
+    Ok((state, error))
+}
 
-                    let res = match &pl.radio_url {
-                        Some(url) => Mplayer::from_url(url),
-                        None => Mplayer::from_list(&pl.files, false, pl.position)
-                    };
+/// Describe the state for the control API
+fn status(state: &State, driver: &Driver) -> hex2::Status {
+    let progress = driver.progress();
+    let (mode, playlist, shuffled) = match state {
+        State::Idle => (Mode::Idle, None, false),
+        State::Playing { playlist, shuffled, .. } => (Mode::Playing, Some(playlist.name.clone()), *shuffled),
+        State::Paused { playlist, shuffled, .. } => (Mode::Paused, Some(playlist.name.clone()), *shuffled),
+        State::Programming { .. } => (Mode::Programming, None, false),
+    };
+
+    let file = driver.player.as_ref()
+        .and_then(|x| x.current_file())
+        .and_then(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()));
+
+    hex2::Status {
+        mode,
+        playlist,
+        track: progress.map(|x| x.track),
+        file,
+        position: progress.map(|x| x.elapsed.as_secs()),
+        shuffled,
+        volume: driver.volume,
+    }
+}
 
-                    match res {
-                        Ok(mplayer) => {
-                            Ok(State::Playing(pl.clone(), mplayer))
-                        },
-                        Err(err) => process_error(state, led_state, 1000, err.into()),
-                    }
-                } else {
-                    // get new card id
-                    let new_card_id = store.next_card_id();
-                    // get all playlists without a corresponding cards
-                    let playlists = store.playlists_without_card();
-                    // find first song for each playlist
-                    let sample_files = playlists
-                        .iter().filter_map(|x| {
-                            store.get_files(&x.name).into_iter().next().clone()
-                        }).collect::<Vec<_>>();
-
-                    // create player from those songs and change state
-                    match Mplayer::from_list(&sample_files, false, None) {
-                        Ok(mplayer) => {
-                            Ok(State::Programming(new_card_id, mplayer, playlists))
-                        },
-                        Err(err) => process_error(state, led_state, 1000, err.into()),
-                    }
-                }
-            },
-            (Event::CardLost, state) => {
-                if let State::Playing(mut pl, mplayer) = state {
-                    store.set_position(&pl.name, mplayer.current_pos())?;
-                }
+/// List the playlists of the library for the control API
+fn playlists(store: &hex2::Store) -> Vec<hex2::PlaylistInfo> {
+    store.playlists().iter()
+        .map(|pl| hex2::PlaylistInfo {
+            name: pl.name.clone(),
+            files: pl.files.iter()
+                .filter_map(|x| x.file_name())
+                .map(|x| x.to_string_lossy().into_owned())
+                .collect(),
+        })
+        .collect()
+}
 
-                Ok(State::Idle)
-            },
-            (Event::ButtonPressed(2), State::Playing(id, mut player)) => {
-                if player.has_next() {
-                    player.next()?;
+/// Run the Zyklop until the input source ends
+///
+/// Returns true if the Zyklop should power off afterwards.
+fn process(path: &str, config: &Config, led_state: &Sender<led::State>) -> Result<bool> {
+    match DeviceInfo::new() {
+        Ok(device_info) => println!("Starting Zyklop on device {}", device_info.model()),
+        Err(_) => println!("Starting Zyklop on a device which is not a Raspberry Pi"),
+    }
 
-                    Ok(State::Playing(id, player))
-                } else {
-                    process_error(State::Playing(id, player), led_state, 2000, StoreError::ReachedEndOfPlaylist.into())
-                }
-            },
-            (Event::ButtonPressed(0), State::Playing(id, mut player)) => {
-                if player.has_prev() {
-                    player.prev()?;
+    // two Zyklops would fight over the card reader, the sound card and the positions
+    let socket = hex2::socket_path(path);
+    if control::is_running(&socket) {
+        bail!("another Zyklop is running on {}", path);
+    }
 
-                    Ok(State::Playing(id, player))
-                } else {
-                    process_error(State::Playing(id, player), led_state, 2000, StoreError::ReachedBeginningOfPlaylist.into())
-                }
-            },
-            (Event::ButtonPressed(1), State::Playing(pl, player)) => {
-                if pl.allow_random {
-                    let was_shuffled = player.is_shuffled();
-                    drop(player);
-
-                    match Mplayer::from_list(&store.get_files(&pl.name), !was_shuffled, None) {
-                        Ok(mplayer) => {
-                            Ok(State::Playing(pl, mplayer))
-                        },
-                        Err(err) => process_error(State::Idle, led_state, 1000, err.into()),
-                    }
-                } else {
-                    process_error(State::Playing(pl, player), led_state, 2000, StoreError::RandomNotAllowed.into())
-                }
-            },
-            (Event::ButtonPressed(2), State::Programming(card_id, mut player, pls)) => {
-                if player.has_next() {
-                    player.next()?;
+    // spawn events thread
+    let (events_out, events_in, events_thread) = events::spawn_events_thread(input_source(config)?);
 
-                    Ok(State::Programming(card_id, player, pls))
-                } else {
-                    process_error(State::Programming(card_id, player, pls), led_state, 2000, StoreError::ReachedEndOfPlaylist.into())
-                }
-            },
-            (Event::ButtonPressed(0), State::Programming(card_id, mut player, pls)) => {
-                if player.has_prev() {
-                    player.prev()?;
+    // open music storage
+    let store = hex2::Store::from_path(path)?;
+    let mut machine = StateMachine::new(store.playlists().to_vec(), config);
+    let mut driver = Driver { store, backend: audio_backend(path, config)?, player: None, led_state, events_in, volume: config.volume.default, shutdown: false };
+
+    // the Zyklop is still usable with buttons and cards, if the servers can't be started
+    let (calls_in, calls) = channel();
+    if let Err(err) = control::spawn_control_thread(&socket, calls_in.clone()) {
+        eprintln!("control API not available: {:?}", err);
+    }
+    if config.mpd.enabled {
+        if let Err(err) = mpd::spawn_mpd_thread(config.mpd.address, calls_in) {
+            eprintln!("MPD server not available: {:?}", err);
+        }
+    }
 
-                    Ok(State::Programming(card_id, player, pls))
-                } else {
-                    process_error(State::Programming(card_id, player, pls), led_state, 2000, StoreError::ReachedBeginningOfPlaylist.into())
-                }
+    run(&mut machine, &mut driver, events_out, events_thread, calls)
+}
+
+/// Feed inputs and requests to the state machine, until the input source ends
+///
+/// Returns true if the Zyklop should power off afterwards.
+fn run(machine: &mut StateMachine, driver: &mut Driver, events_out: Receiver<Event>, events_thread: JoinHandle<Result<()>>, calls: Receiver<control::Call>) -> Result<bool> {
+    let mut state = State::Idle;
+    driver.led_state.send(state::led_state(&state))?;
+
+    loop {
+        // requests of the control API are handled between the inputs
+        while let Ok((request, reply)) = calls.try_recv() {
+            let command = match request {
+                Request::Status => {
+                    let _ = reply.send(Response::Status(status(&state, driver)));
+                    continue;
+                },
+                Request::Playlists => {
+                    let _ = reply.send(Response::Playlists { playlists: playlists(&driver.store) });
+                    continue;
+                },
+                Request::Play { playlist } => Command::Play(playlist),
+                Request::Next => Command::Next,
+                Request::Prev => Command::Prev,
+                Request::Pause => Command::Pause,
+                Request::Resume => Command::Resume,
+                Request::Stop => Command::Stop,
+                Request::Shuffle => Command::Shuffle,
+                Request::Program { playlist } => Command::Program(playlist),
+                Request::SetVolume { volume } => Command::SetVolume(volume),
+            };
+
+            let (new_state, error) = step(machine, driver, state, Input::Command(command))?;
+            state = new_state;
+
+            let _ = reply.send(match error {
+                Some(message) => Response::Error { message },
+                None => Response::Ok,
+            });
+        }
+
+        let input = match events_out.recv_timeout(Duration::from_millis(50)) {
+            Ok(event) => Input::Event(event),
+            Err(RecvTimeoutError::Timeout) => match driver.player.as_mut().and_then(|x| x.poll_event()) {
+                Some(event) => Input::Player(event),
+                None => Input::Tick(Instant::now()),
             },
-            (Event::ButtonPressed(1), State::Programming(card_id, player, pls)) => {
-                let playlist = pls[player.current_pos()].clone();
-                store.set_playlist_card_id(&playlist.name, card_id)?;
-                events_in.send(card_id)?;
-                store.save()?;
-
-                match Mplayer::from_list(&playlist.files, false, None) {
-                    Ok(mplayer) => {
-                        Ok(State::Playing(playlist, mplayer))
-                    },
-                    Err(err) => process_error(State::Idle, led_state, 1000, err.into()),
-                }
+            Err(RecvTimeoutError::Disconnected) => {
+                // either the input source ended, like a script, or it failed
+                return match events_thread.join() {
+                    Ok(res) => res.context("input source failed").map(|_| false),
+                    Err(_) => Err(anyhow!("events thread panicked")),
+                };
             },
-            (_, state) => Ok(state)
         };
 
-        // update the LED to the new state
-        match &new_state {
-            Ok(State::Idle) => led_state.send(led::State::Continuous(Color(255, 255, 255, 255)))?,
-            Ok(State::Playing(pl, _)) => {
-                if pl.radio_url.is_some() {
-                    led_state.send(led::State::Continuous(led::Color(0, 255, 255, 255)))?
-                } else {
-                    led_state.send(led::State::Continuous(led::Color(0, 0, 255, 255)))?
-                }
-            },
-            Ok(State::Programming(..)) => led_state.send(led::State::Continuous(Color(255, 255, 0, 255)))?,
-            _ => {}
+        state = step(machine, driver, state, input)?.0;
+
+        if driver.shutdown {
+            driver.shut_down()?;
+
+            return Ok(true);
         }
+    }
+}
 
-        state = new_state?;
+/// Flush the file system and run the power off command
+///
+/// Failures are only printed, there is nobody left to handle them.
+fn power_off(config: &config::Power) {
+    if let Err(err) = std::process::Command::new("sync").status() {
+        eprintln!("could not flush the file system: {}", err);
+    }
+
+    println!("Powering off with `{}`", config.command.join(" "));
+
+    let status = std::process::Command::new(&config.command[0])
+        .args(&config.command[1..])
+        .status();
+    match status {
+        Ok(status) if !status.success() => eprintln!("power off command failed with {}", status),
+        Err(err) => eprintln!("could not power off: {}", err),
+        Ok(_) => {},
     }
 }
 
 fn main() -> Result<()> {
-    let (led_state, led_thread) = led::spawn_led_thread()?;
-    let res = process(&led_state);
+    let path = std::env::var("ZYKLOP_PATH")
+        .map_err(|_| anyhow!("could not find path in `ZYKLOP_PATH`"))?;
+
+    // the hardware profile is needed before anything else, because it contains the LED pins
+    let config = Config::load(&path)?;
+
+    let (led_state, led_thread) = match led::spawn_led_thread(&config.led) {
+        Ok(led) => led,
+        Err(err) => {
+            eprintln!("LED ring not available: {}", err);
+            led::spawn_null_thread()
+        }
+    };
+    let res = process(&path, &config, &led_state);
+    let _ = std::fs::remove_file(hex2::socket_path(&path));
 
     // we bailed out either because of an error or because we are shutting down the Zyklop
-    if res.is_ok() {
+    if let Ok(shut_down) = res {
         led_state.send(led::State::Sine(led::Color(255, 255, 255, 255), 2000.0))?;
+
+        if shut_down {
+            // give the animation some time, the library was already saved by the driver
+            thread::sleep(Duration::from_millis(2000));
+            power_off(&config.power);
+        }
     } else {
         led_state.send(led::State::Continuous(led::Color(255, 0, 0, 255)))?;
         eprintln!("{:?}", res);
     }
 
+    drop(led_state);
     led_thread.join().unwrap();
 
-    res
+    res.map(|_| ())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    /// Player recording the volumes it is set to
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl Player for Recorder {
+        fn next(&mut self) -> hex2::Result<()> { Ok(()) }
+        fn prev(&mut self) -> hex2::Result<()> { Ok(()) }
+        fn seek(&mut self, _: i64) -> hex2::Result<()> { Ok(()) }
+        fn pause(&mut self) -> hex2::Result<()> { Ok(()) }
+        fn resume(&mut self) -> hex2::Result<()> { Ok(()) }
+
+        fn set_volume(&mut self, volume: u8) -> hex2::Result<()> {
+            self.0.lock().unwrap().push(volume);
+
+            Ok(())
+        }
+
+        fn current_pos(&self) -> usize { 0 }
+        fn current_file(&self) -> Option<PathBuf> { None }
+        fn elapsed(&self) -> Duration { Duration::from_secs(0) }
+        fn poll_event(&mut self) -> Option<audio::PlayerEvent> { None }
+    }
+
+    #[test]
+    fn shut_down_fades_out() {
+        let path = std::env::temp_dir().join(format!("zyklop-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let (led_state, _led) = channel();
+        let (events_in, _events) = channel();
+        let volumes = Arc::new(Mutex::new(Vec::new()));
+        let store = hex2::Store::create(&path).unwrap();
+        let backend = Box::new(Engine::new(OutputKind::Null, hex2::Gains::default(), config::Normalize::Album));
+        let player: Box<dyn Player> = Box::new(Recorder(volumes.clone()));
+        let mut driver = Driver { store, backend, player: Some(player), led_state: &led_state, events_in, volume: 60, shutdown: true };
+
+        driver.shut_down().unwrap();
+        assert!(driver.player.is_none());
+
+        let volumes = volumes.lock().unwrap();
+        assert_eq!((volumes.len(), volumes[0], volumes[volumes.len() - 1]), (30, 58, 0));
+        assert!(volumes.windows(2).all(|x| x[0] >= x[1]));
+    }
+
+    #[test]
+    fn power_off_runs_command() {
+        let marker = std::env::temp_dir().join(format!("zyklop-power-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+
+        let power = config::Power { command: vec!["touch".into(), marker.to_string_lossy().into_owned()], ..config::Power::default() };
+        power_off(&power);
+        assert!(marker.exists());
+
+        // a missing command is only reported
+        power_off(&config::Power { command: vec!["/nonexistent/poweroff".into()], ..config::Power::default() });
+    }
+
+    /// Create a workspace with the playlist `book` on card 0, with two tracks of some seconds
+    pub fn workspace(name: &str, seconds: f32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("zyklop-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let mut store = hex2::Store::create(&path).unwrap();
+        std::fs::create_dir_all(path.join("files/book")).unwrap();
+        engine::tests::write_wav(&path.join("files/book/1.wav"), 8192, seconds);
+        engine::tests::write_wav(&path.join("files/book/2.wav"), 8192, seconds);
+
+        let book = hex2::Playlist { card_id: Some(0), ..hex2::Playlist::new("book") };
+        store.add_playlists(vec![book]).unwrap();
+
+        path
+    }
+
+    /// Run a script against a workspace in the background, answering the requests of `calls`
+    ///
+    /// The in-process engine plays silently, but in real time.
+    pub fn spawn_script(path: &Path, script: &str, calls: Receiver<control::Call>) -> JoinHandle<(bool, hex2::Store)> {
+        let path = path.to_path_buf();
+        let input = Box::new(ScriptInput::new(script).unwrap());
+
+        thread::spawn(move || {
+            let config = Config::default();
+            let (events_out, events_in, events_thread) = events::spawn_events_thread(input);
+            let (led_state, _led) = channel();
+
+            let store = hex2::Store::from_path(&path).unwrap();
+            let mut machine = StateMachine::new(store.playlists().to_vec(), &config);
+            let backend = Box::new(Engine::new(OutputKind::Null, hex2::Gains::default(), config.volume.normalize));
+            let mut driver = Driver { store, backend, player: None, led_state: &led_state, events_in, volume: config.volume.default, shutdown: false };
+
+            let shut_down = run(&mut machine, &mut driver, events_out, events_thread, calls).unwrap();
+
+            (shut_down, driver.store)
+        })
+    }
+
+    /// Run a script against a workspace, without any clients of the control API
+    fn run_script(path: &Path, script: &str) -> (bool, hex2::Store) {
+        let (_calls_in, calls) = channel();
+
+        spawn_script(path, script, calls).join().unwrap()
+    }
+
+    #[test]
+    fn skip_and_remove_card() {
+        let path = workspace("script-skip", 0.5);
+
+        // the removed card only pauses, but the position is on disk already
+        let (shut_down, _store) = run_script(&path, "card 0\nwait 200\nbutton 2\nwait 100\nlost\nwait 100\n");
+        assert!(!shut_down);
+
+        let positions = hex2::Positions::from_path(&path);
+        assert_eq!(positions.playlists["book"].file, "2.wav");
+        assert_eq!(positions.playlists["book"].track, 1);
+    }
+
+    #[test]
+    fn finish_playlist() {
+        let path = workspace("script-finish", 0.5);
+
+        // a finished playlist starts from the beginning next time
+        let (_, store) = run_script(&path, "card 0\nwait 200\nbutton 2\nwait 1000\n");
+        assert_eq!(store.playlists()[0].position, Some((0, 0)));
+    }
+
+    #[test]
+    fn power_button_shuts_down() {
+        let path = workspace("script-power", 2.0);
+
+        // the loop ends with the shut down, the last button is never handled
+        let (shut_down, _store) = run_script(&path, "card 0\nwait 200\npower pressed\npower released\nwait 100\nbutton 2\n");
+        assert!(shut_down);
+
+        let positions = hex2::Positions::from_path(&path);
+        assert_eq!(positions.playlists["book"].file, "1.wav");
+        assert_eq!(positions.playlists["book"].track, 0);
+    }
+
+    #[test]
+    fn control_socket() {
+        let path = workspace("script-control", 10.0);
+        let socket = hex2::socket_path(&path);
+        let (calls_in, calls) = channel();
+        control::spawn_control_thread(&socket, calls_in).unwrap();
+        let zyklop = spawn_script(&path, "wait 500\n", calls);
+
+        let status = |socket: &Path| match Request::Status.send(socket).unwrap() {
+            Response::Status(status) => (status.mode, status.playlist, status.track),
+            response => panic!("no status: {:?}", response),
+        };
+        assert_eq!(status(&socket), (Mode::Idle, None, None));
+
+        assert_eq!(Request::Play { playlist: "book".into() }.send(&socket).unwrap(), Response::Ok);
+        assert_eq!(Request::Next.send(&socket).unwrap(), Response::Ok);
+        assert_eq!(status(&socket), (Mode::Playing, Some("book".into()), Some(1)));
+        assert!(matches!(Request::Play { playlist: "missing".into() }.send(&socket).unwrap(), Response::Error { .. }));
+
+        // the running Zyklop keeps its socket
+        assert!(control::is_running(&socket));
+        assert!(control::spawn_control_thread(&socket, channel().0).is_err());
+
+        assert!(!zyklop.join().unwrap().0);
+    }
+
 }
@@ -0,0 +1,704 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hex2::{Mode, PlaylistInfo, Request, Response, Status};
+
+use crate::control::{self, Call};
+
+/// Sent to every client after connecting, older versions keep clients from using newer commands
+const GREETING: &str = "OK MPD 0.19.0\n";
+
+/// How often the status is compared while a client idles
+const IDLE_POLL: Duration = Duration::from_millis(250);
+
+/// Error codes of the MPD protocol
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+
+/// Commands understood by the server, as listed by `commands`
+const COMMANDS: &[&str] = &[
+    "clear", "close", "command_list_begin", "command_list_end", "command_list_ok_begin",
+    "commands", "currentsong", "idle", "listplaylist", "listplaylistinfo", "listplaylists",
+    "load", "lsinfo", "next", "noidle", "notcommands", "outputs", "pause", "ping", "play",
+    "playid", "playlistinfo", "plchanges", "previous", "random", "setvol", "stats", "status",
+    "stop", "tagtypes",
+];
+
+/// Error of a single command, sent as `ACK [code@index] {command} message`
+#[derive(Debug, PartialEq)]
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    fn new<T: Into<String>>(code: u32, message: T) -> Ack {
+        Ack { code, message: message.into() }
+    }
+}
+
+/// Serve a subset of the MPD protocol on a TCP port
+///
+/// Every client gets its own thread. MPD commands are translated into requests of the control
+/// API, so MPD clients and `odysseus remote` act exactly the same. The queue of MPD is always a
+/// single playlist: `load` starts playing a playlist right away and `play` after `stop` continues
+/// the playlist stopped last.
+///
+/// Returns the address the server listens on, with the port chosen by the system if it was zero.
+pub fn spawn_mpd_thread(address: SocketAddr, calls: Sender<Call>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("could not listen for MPD clients on {}", address))?;
+    let address = listener.local_addr()?;
+
+    // playlist which was stopped last, shared by all clients
+    let last = Arc::new(Mutex::new(None));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let session = Session { calls: calls.clone(), last: last.clone() };
+                    thread::spawn(move || {
+                        if let Err(err) = session.serve(stream) {
+                            eprintln!("MPD client failed: {:?}", err);
+                        }
+                    });
+                },
+                Err(err) => eprintln!("could not accept MPD client: {:?}", err),
+            }
+        }
+    });
+
+    Ok(address)
+}
+
+/// Connection of a single MPD client
+struct Session {
+    calls: Sender<Call>,
+    /// Playlist resumed by `play`, once the playback was stopped
+    last: Arc<Mutex<Option<String>>>,
+}
+
+impl Session {
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        writer.write_all(GREETING.as_bytes())?;
+
+        // commands of a command list and whether every command is acknowledged by `list_OK`
+        let mut list: Option<(bool, Vec<String>)> = None;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+
+            let answer = match (&mut list, line) {
+                (None, "command_list_begin") => {
+                    list = Some((false, Vec::new()));
+                    continue;
+                },
+                (None, "command_list_ok_begin") => {
+                    list = Some((true, Vec::new()));
+                    continue;
+                },
+                (Some(_), "command_list_end") => {
+                    let (list_ok, lines) = list.take().unwrap();
+                    self.run(&lines, list_ok)
+                },
+                (Some((_, lines)), line) => {
+                    lines.push(line.to_string());
+                    continue;
+                },
+                (None, "close") => return Ok(()),
+                (None, line) if line == "idle" || line.starts_with("idle ") => match self.idle(&mut reader, line)? {
+                    Some(answer) => answer,
+                    None => return Ok(()),
+                },
+                (None, line) => self.run(&[line.to_string()], false),
+            };
+
+            writer.write_all(answer.as_bytes())?;
+        }
+    }
+
+    /// Execute a list of commands and return the answer, stopping at the first error
+    fn run(&self, lines: &[String], list_ok: bool) -> String {
+        let mut answer = String::new();
+
+        for (index, line) in lines.iter().enumerate() {
+            let args = match tokenize(line) {
+                Some(args) if !args.is_empty() => args,
+                _ => {
+                    answer.push_str(&format!("ACK [{}@{}] {{}} invalid command line\n", ACK_ERROR_ARG, index));
+                    return answer;
+                },
+            };
+
+            if let Err(ack) = self.execute(&args[0], &args[1..], &mut answer) {
+                answer.push_str(&format!("ACK [{}@{}] {{{}}} {}\n", ack.code, index, args[0], ack.message));
+                return answer;
+            }
+
+            if list_ok {
+                answer.push_str("list_OK\n");
+            }
+        }
+
+        answer.push_str("OK\n");
+        answer
+    }
+
+    /// Wait until the playback changes or the client sends `noidle`
+    ///
+    /// Only changes of the subsystems named by the client are reported, all if it names none.
+    /// Returns `None` if the client disconnected.
+    fn idle(&self, reader: &mut BufReader<TcpStream>, command: &str) -> Result<Option<String>> {
+        let subsystems = tokenize(command).unwrap_or_default().split_off(1);
+        let before = self.status().ok();
+
+        reader.get_ref().set_read_timeout(Some(IDLE_POLL))?;
+
+        // only `noidle` is allowed while idling, anything else ends the idle as well
+        let mut line = String::new();
+        let changed = loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break Vec::new(),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                    let mut changed = changes(before.as_ref(), self.status().ok().as_ref());
+                    changed.retain(|x| subsystems.is_empty() || subsystems.iter().any(|y| y == x));
+                    if !changed.is_empty() {
+                        break changed;
+                    }
+                },
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        reader.get_ref().set_read_timeout(None)?;
+
+        let mut answer = changed.iter().map(|x| format!("changed: {}\n", x)).collect::<String>();
+        answer.push_str("OK\n");
+
+        Ok(Some(answer))
+    }
+
+    /// Execute a single command, appending its output to the answer
+    fn execute(&self, command: &str, args: &[String], out: &mut String) -> std::result::Result<(), Ack> {
+        match command {
+            "ping" | "noidle" | "clearerror" | "notcommands" | "urlhandlers" | "decoders" => {},
+            "commands" => {
+                for command in COMMANDS {
+                    out.push_str(&format!("command: {}\n", command));
+                }
+            },
+            "tagtypes" => out.push_str("tagtype: Title\ntagtype: Album\n"),
+            "outputs" => out.push_str("outputid: 0\noutputname: Zyklop\noutputenabled: 1\n"),
+            "stats" => {
+                let playlists = self.playlists()?;
+                let songs = playlists.iter().map(|x| x.files.len()).sum::<usize>();
+
+                out.push_str(&format!("artists: 0\nalbums: {}\nsongs: {}\nuptime: 0\nplaytime: 0\ndb_playtime: 0\ndb_update: 0\n", playlists.len(), songs));
+            },
+            "status" => {
+                let status = self.status()?;
+                let queue = self.queue(&status)?;
+
+                out.push_str(&format_status(&status, queue.as_ref()));
+            },
+            "currentsong" => {
+                let status = self.status()?;
+
+                if let (Mode::Playing, Some(playlist), Some(track)) | (Mode::Paused, Some(playlist), Some(track)) = (status.mode, &status.playlist, status.track) {
+                    let file = status.file.as_deref().unwrap_or_default();
+                    out.push_str(&format_song(playlist, file, track));
+                }
+            },
+            "playlistinfo" | "plchanges" => {
+                let status = self.status()?;
+
+                if let Some(queue) = self.queue(&status)? {
+                    for (pos, file) in queue.files.iter().enumerate() {
+                        out.push_str(&format_song(&queue.name, file, pos));
+                    }
+                }
+            },
+            "listplaylists" => {
+                for pl in self.playlists()? {
+                    out.push_str(&format!("playlist: {}\n", pl.name));
+                }
+            },
+            "lsinfo" => match args.first().map(|x| x.trim_matches('/')).filter(|x| !x.is_empty()) {
+                Some(name) => {
+                    let pl = self.playlist(name)?;
+                    for (pos, file) in pl.files.iter().enumerate() {
+                        out.push_str(&format_song(&pl.name, file, pos));
+                    }
+                },
+                None => {
+                    for pl in self.playlists()? {
+                        out.push_str(&format!("playlist: {}\n", pl.name));
+                    }
+                },
+            },
+            "listplaylist" | "listplaylistinfo" => {
+                let pl = self.playlist(argument(args, 0)?)?;
+
+                for (pos, file) in pl.files.iter().enumerate() {
+                    if command == "listplaylist" {
+                        out.push_str(&format!("file: {}/{}\n", pl.name, file));
+                    } else {
+                        out.push_str(&format_song(&pl.name, file, pos));
+                    }
+                }
+            },
+            "load" => {
+                let pl = self.playlist(argument(args, 0)?)?;
+
+                self.request(Request::Play { playlist: pl.name.clone() })?;
+                *self.last.lock().unwrap() = Some(pl.name);
+            },
+            "play" | "playid" => {
+                let target = match args.first() {
+                    Some(arg) => Some(arg.parse::<usize>().map_err(|_| Ack::new(ACK_ERROR_ARG, format!("need a positive integer, got {}", arg)))?),
+                    None => None,
+                };
+
+                let status = self.resume()?;
+                if let (Some(target), Some(track)) = (target, status.track) {
+                    self.skip(&status, track, target)?;
+                }
+            },
+            "pause" => {
+                let status = self.status()?;
+                let pause = match args.first().map(|x| x.as_str()) {
+                    Some("1") => true,
+                    Some("0") => false,
+                    None => status.mode == Mode::Playing,
+                    Some(arg) => return Err(Ack::new(ACK_ERROR_ARG, format!("need 0 or 1, got {}", arg))),
+                };
+
+                if pause && status.mode == Mode::Playing {
+                    self.request(Request::Pause)?;
+                } else if !pause {
+                    self.resume()?;
+                }
+            },
+            "stop" | "clear" => {
+                let status = self.status()?;
+                self.stop(&status)?;
+            },
+            "next" => { self.request(Request::Next)?; },
+            "previous" => { self.request(Request::Prev)?; },
+            "random" => {
+                let random = match argument(args, 0)? {
+                    "1" => true,
+                    "0" => false,
+                    arg => return Err(Ack::new(ACK_ERROR_ARG, format!("need 0 or 1, got {}", arg))),
+                };
+
+                if self.status()?.shuffled != random {
+                    self.request(Request::Shuffle)?;
+                }
+            },
+            "setvol" => {
+                let arg = argument(args, 0)?;
+                let volume = arg.parse::<u8>().ok().filter(|x| *x <= 100)
+                    .ok_or_else(|| Ack::new(ACK_ERROR_ARG, format!("need a volume between 0 and 100, got {}", arg)))?;
+
+                self.request(Request::SetVolume { volume })?;
+            },
+            _ => return Err(Ack::new(ACK_ERROR_UNKNOWN, format!("unknown command \"{}\"", command))),
+        }
+
+        Ok(())
+    }
+
+    /// Send a request to the main loop, errors of the Zyklop are passed on to the client
+    fn request(&self, request: Request) -> std::result::Result<Response, Ack> {
+        match control::call(&self.calls, request) {
+            Some(Response::Error { message }) => Err(Ack::new(ACK_ERROR_SYSTEM, message)),
+            Some(response) => Ok(response),
+            None => Err(Ack::new(ACK_ERROR_SYSTEM, "Zyklop is shutting down")),
+        }
+    }
+
+    fn status(&self) -> std::result::Result<Status, Ack> {
+        match self.request(Request::Status)? {
+            Response::Status(status) => Ok(status),
+            _ => Err(Ack::new(ACK_ERROR_SYSTEM, "unexpected response")),
+        }
+    }
+
+    fn playlists(&self) -> std::result::Result<Vec<PlaylistInfo>, Ack> {
+        match self.request(Request::Playlists)? {
+            Response::Playlists { playlists } => Ok(playlists),
+            _ => Err(Ack::new(ACK_ERROR_SYSTEM, "unexpected response")),
+        }
+    }
+
+    fn playlist(&self, name: &str) -> std::result::Result<PlaylistInfo, Ack> {
+        self.playlists()?.into_iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, "No such playlist"))
+    }
+
+    /// The queue is the playing playlist, or the one stopped last
+    fn queue(&self, status: &Status) -> std::result::Result<Option<PlaylistInfo>, Ack> {
+        let name = status.playlist.clone().or_else(|| self.last.lock().unwrap().clone());
+
+        match name {
+            Some(name) => Ok(self.playlists()?.into_iter().find(|x| x.name == name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stop the playback, remembering the playlist to resume it later
+    fn stop(&self, status: &Status) -> std::result::Result<(), Ack> {
+        if status.mode == Mode::Playing || status.mode == Mode::Paused {
+            *self.last.lock().unwrap() = status.playlist.clone();
+            self.request(Request::Stop)?;
+        }
+
+        Ok(())
+    }
+
+    /// Continue a paused playback or the playlist stopped last, and return the new status
+    fn resume(&self) -> std::result::Result<Status, Ack> {
+        let status = self.status()?;
+        match status.mode {
+            Mode::Playing => return Ok(status),
+            Mode::Paused => {
+                self.request(Request::Resume)?;
+                return self.status();
+            },
+            Mode::Idle | Mode::Programming => {},
+        }
+
+        let name = self.last.lock().unwrap().clone()
+            .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, "No playlist loaded"))?;
+        self.request(Request::Play { playlist: name })?;
+
+        self.status()
+    }
+
+    /// Step through the playlist until `target` is reached, there is no way to jump directly
+    fn skip(&self, status: &Status, track: usize, target: usize) -> std::result::Result<(), Ack> {
+        let length = self.queue(status)?.map(|x| x.files.len()).unwrap_or(0);
+        if target >= length {
+            return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+        }
+
+        for _ in track..target {
+            self.request(Request::Next)?;
+        }
+        for _ in target..track {
+            self.request(Request::Prev)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Return the argument at `index`, or an error naming the missing argument
+fn argument(args: &[String], index: usize) -> std::result::Result<&str, Ack> {
+    args.get(index)
+        .map(|x| x.as_str())
+        .ok_or_else(|| Ack::new(ACK_ERROR_ARG, "missing argument"))
+}
+
+/// Split a command line into the command and its arguments
+///
+/// Arguments are separated by whitespace and may be quoted, with `\"` and `\\` escaping within
+/// quotes. Returns `None` for an unterminated quote.
+fn tokenize(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek().map(|x| x.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+
+        let mut arg = String::new();
+        match chars.peek() {
+            None => return Some(args),
+            Some('"') => {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => arg.push(chars.next()?),
+                        c => arg.push(c),
+                    }
+                }
+            },
+            Some(_) => {
+                while let Some(c) = chars.peek().filter(|x| !x.is_whitespace()) {
+                    arg.push(*c);
+                    chars.next();
+                }
+            },
+        }
+
+        args.push(arg);
+    }
+}
+
+/// Describe the status of the Zyklop, with the queue being a playlist
+fn format_status(status: &Status, queue: Option<&PlaylistInfo>) -> String {
+    let state = match status.mode {
+        Mode::Playing => "play",
+        Mode::Paused => "pause",
+        Mode::Idle | Mode::Programming => "stop",
+    };
+
+    // clients reload the queue when its version changes
+    let version = queue.map(|x| {
+        let mut hasher = DefaultHasher::new();
+        x.name.hash(&mut hasher);
+        hasher.finish() as u32
+    }).unwrap_or(0);
+
+    let mut out = format!(
+        "volume: {}\nrepeat: 0\nrandom: {}\nsingle: 0\nconsume: 0\nplaylist: {}\nplaylistlength: {}\nstate: {}\n",
+        status.volume, status.shuffled as u8, version, queue.map(|x| x.files.len()).unwrap_or(0), state,
+    );
+
+    if let (Mode::Playing, Some(track)) | (Mode::Paused, Some(track)) = (status.mode, status.track) {
+        // `time` would need the duration of the track, which is not known
+        out.push_str(&format!("song: {}\nsongid: {}\nelapsed: {}.000\n", track, track, status.position.unwrap_or(0)));
+    }
+
+    out
+}
+
+/// Describe a track of a playlist, its position doubles as id
+fn format_song(playlist: &str, file: &str, pos: usize) -> String {
+    let title = Path::new(file).file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+
+    format!("file: {}/{}\nTitle: {}\nAlbum: {}\nPos: {}\nId: {}\n", playlist, file, title, playlist, pos, pos)
+}
+
+/// Return the subsystems which changed between two status, as reported by `idle`
+fn changes(before: Option<&Status>, after: Option<&Status>) -> Vec<&'static str> {
+    let (before, after) = match (before, after) {
+        (Some(before), Some(after)) => (before, after),
+        // the Zyklop is shutting down, the next command will tell the client
+        _ => return vec!["player"],
+    };
+
+    let mut changed = Vec::new();
+    if before.playlist != after.playlist {
+        changed.push("playlist");
+    }
+    if before.mode != after.mode || before.playlist != after.playlist || before.track != after.track {
+        changed.push("player");
+    }
+    if before.volume != after.volume {
+        changed.push("mixer");
+    }
+    if before.shuffled != after.shuffled {
+        changed.push("options");
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(mode: Mode, track: Option<usize>, volume: u8) -> Status {
+        Status {
+            mode,
+            playlist: track.map(|_| "Kids".into()),
+            track,
+            file: track.map(|_| "01 Intro.ogg".into()),
+            position: track.map(|_| 42),
+            shuffled: false,
+            volume,
+        }
+    }
+
+    #[test]
+    fn tokenize_plain_arguments() {
+        assert_eq!(tokenize("setvol  30 "), Some(vec!["setvol".into(), "30".into()]));
+        assert_eq!(tokenize(""), Some(vec![]));
+    }
+
+    #[test]
+    fn tokenize_quoted_arguments() {
+        assert_eq!(tokenize(r#"load "Bedtime Stories""#), Some(vec!["load".into(), "Bedtime Stories".into()]));
+        assert_eq!(tokenize(r#"load "say \"hi\" \\o/""#), Some(vec!["load".into(), r#"say "hi" \o/"#.into()]));
+        assert_eq!(tokenize(r#"load """#), Some(vec!["load".into(), "".into()]));
+    }
+
+    #[test]
+    fn tokenize_rejects_unterminated_quote() {
+        assert_eq!(tokenize(r#"load "Kids"#), None);
+    }
+
+    #[test]
+    fn song_has_playlist_as_album() {
+        assert_eq!(
+            format_song("Kids", "01 Intro.ogg", 3),
+            "file: Kids/01 Intro.ogg\nTitle: 01 Intro\nAlbum: Kids\nPos: 3\nId: 3\n",
+        );
+    }
+
+    #[test]
+    fn status_of_idle_zyklop() {
+        let out = format_status(&status(Mode::Idle, None, 50), None);
+
+        assert!(out.contains("state: stop\n"));
+        assert!(out.contains("volume: 50\n"));
+        assert!(out.contains("playlistlength: 0\n"));
+        assert!(!out.contains("song:"));
+    }
+
+    #[test]
+    fn status_of_playing_zyklop() {
+        let queue = PlaylistInfo { name: "Kids".into(), files: vec!["a.ogg".into(), "b.ogg".into()] };
+        let out = format_status(&status(Mode::Playing, Some(1), 50), Some(&queue));
+
+        assert!(out.contains("state: play\n"));
+        assert!(out.contains("playlistlength: 2\n"));
+        assert!(out.contains("song: 1\n"));
+        assert!(out.contains("elapsed: 42.000\n"));
+        assert!(!out.contains("time:"));
+    }
+
+    #[test]
+    fn changes_ignore_progress() {
+        let before = status(Mode::Playing, Some(1), 50);
+        let mut after = before.clone();
+        after.position = Some(43);
+
+        assert!(changes(Some(&before), Some(&after)).is_empty());
+    }
+
+    #[test]
+    fn changes_name_subsystems() {
+        let before = status(Mode::Playing, Some(1), 50);
+
+        assert_eq!(changes(Some(&before), Some(&status(Mode::Playing, Some(2), 50))), vec!["player"]);
+        assert_eq!(changes(Some(&before), Some(&status(Mode::Playing, Some(1), 60))), vec!["mixer"]);
+        assert_eq!(changes(Some(&before), Some(&status(Mode::Idle, None, 50))), vec!["playlist", "player"]);
+        assert_eq!(changes(Some(&before), None), vec!["player"]);
+    }
+
+    /// Start a MPD server in front of a Zyklop, which runs for a minute
+    ///
+    /// The Zyklop has the playlist `book` with two tracks of half a minute.
+    fn spawn_zyklop(name: &str) -> SocketAddr {
+        let path = crate::tests::workspace(name, 30.0);
+        let (calls_in, calls) = std::sync::mpsc::channel();
+        let address = spawn_mpd_thread(SocketAddr::from(([127, 0, 0, 1], 0)), calls_in).unwrap();
+        crate::tests::spawn_script(&path, "wait 60000\n", calls);
+
+        address
+    }
+
+    /// Client sending commands and reading answers up to the final `OK` or `ACK`
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Client {
+            let stream = TcpStream::connect(address).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            assert_eq!(greeting, GREETING);
+
+            Client { reader, writer: stream }
+        }
+
+        fn send(&mut self, lines: &str) -> String {
+            self.writer.write_all(lines.as_bytes()).unwrap();
+
+            let mut answer = String::new();
+            loop {
+                let start = answer.len();
+                self.reader.read_line(&mut answer).unwrap();
+                if answer[start..] == *"OK\n" || answer[start..].starts_with("ACK ") {
+                    return answer;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serve_client() {
+        let mut client = Client::connect(spawn_zyklop("mpd-client"));
+
+        let answer = client.send("status\n");
+        assert!(answer.starts_with("volume: 50\n"), "{}", answer);
+        assert!(answer.contains("state: stop\n"));
+
+        // loading starts the playback, playing a position skips to it
+        assert_eq!(client.send("load book\n"), "OK\n");
+        assert_eq!(client.send("play 1\n"), "OK\n");
+        let answer = client.send("status\n");
+        assert!(answer.contains("playlistlength: 2\nstate: play\nsong: 1\n"), "{}", answer);
+        assert!(client.send("currentsong\n").starts_with("file: book/2.wav\n"));
+        assert_eq!(client.send("load missing\n"), "ACK [50@0] {load} No such playlist\n");
+
+        // errors of the Zyklop are passed on
+        assert!(client.send("next\n").starts_with("ACK [52@0] {next} "));
+
+        assert_eq!(client.send("pause\n"), "OK\n");
+        assert!(client.send("status\n").contains("state: pause\n"));
+        assert_eq!(client.send("pause 0\n"), "OK\n");
+        assert!(client.send("status\n").contains("state: play\n"));
+
+        // play after stop continues the playlist stopped last
+        assert_eq!(client.send("stop\n"), "OK\n");
+        assert!(client.send("status\n").contains("state: stop\n"));
+        assert_eq!(client.send("play\n"), "OK\n");
+        assert!(client.send("status\n").contains("playlistlength: 2\nstate: play\n"));
+
+        // the Zyklop caps the volume to its maximum
+        assert_eq!(client.send("setvol 30\n"), "OK\n");
+        assert!(client.send("status\n").starts_with("volume: 30\n"));
+        assert_eq!(client.send("setvol 101\n"), "ACK [2@0] {setvol} need a volume between 0 and 100, got 101\n");
+        assert_eq!(client.send("setvol 90\n"), "OK\n");
+        assert!(client.send("status\n").starts_with("volume: 80\n"));
+    }
+
+    #[test]
+    fn serve_command_lists() {
+        let mut client = Client::connect(spawn_zyklop("mpd-lists"));
+
+        let answer = client.send("command_list_ok_begin\nsetvol 20\nstatus\ncommand_list_end\n");
+        assert!(answer.starts_with("list_OK\nvolume: 20\n"), "{}", answer);
+        assert!(answer.ends_with("state: stop\nlist_OK\nOK\n"), "{}", answer);
+
+        // the list stops at the first failing command
+        let answer = client.send("command_list_begin\nload book\nbogus\nnext\ncommand_list_end\n");
+        assert_eq!(answer, "ACK [5@1] {bogus} unknown command \"bogus\"\n");
+        assert!(client.send("status\n").contains("state: play\nsong: 0\n"));
+
+        // the connection still works after an error, until the client closes it
+        assert_eq!(client.send("ping\n"), "OK\n");
+        client.writer.write_all(b"close\n").unwrap();
+        assert_eq!(client.reader.read_line(&mut String::new()).unwrap(), 0);
+    }
+}
@@ -1,14 +1,22 @@
 use std::io::{Read, Write, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Child, Stdio};
-use std::time::Instant;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
 use hex2::{Result, StoreError, Playlist};
 
+use crate::audio::{AudioBackend, Player, PlayerEvent};
+
 pub struct Mplayer {
     handle: Child,
     pos: usize,
-    max_songs: usize,
-    shuffle: bool,
+    /// Played files in order, empty if mplayer shuffles them or plays a stream
+    files: Vec<PathBuf>,
+    /// Files mplayer reported to play, read from its output
+    playing: Receiver<PathBuf>,
+    paused: bool,
+    exited: bool,
 }
 
 impl Mplayer {
@@ -24,13 +32,16 @@ impl Mplayer {
             return Err(StoreError::BinaryMissing("mplayer".into()));
         }
 
+        // without `-idle` mplayer exits after the last file, which finishes the playlist
         let mut main_handle = Command::new("/usr/bin/mplayer");
-        let mut handle = if shuffle {
-            main_handle.arg("-idle").arg("-shuffle")
+        let handle = if shuffle {
+            main_handle.arg("-shuffle")
         } else {
-            main_handle.arg("-idle")
+            &mut main_handle
         };
 
+        let order = if shuffle { Vec::new() } else { files.to_vec() };
+
         // convert pathbuf to strings and insert position if necessary
         let files = files.into_iter().map(|x| x.to_string_lossy().to_string()).collect::<Vec<_>>();
 
@@ -53,31 +64,9 @@ impl Mplayer {
             }
         }
 
-        let stdout = handle.stdout.as_mut().unwrap();
-        let stdout_reader = std::io::BufReader::with_capacity(8000 * 100, stdout);
-        let stdout_lines = stdout_reader.lines();
-
-        let mut correct = false;
+        let (handle, playing) = wait_for_playback(handle)?;
 
-        'outer: for line in stdout_lines.into_iter().filter_map(|x| x.ok()) {
-            if line.contains("Starting playback...") {
-                correct = true;
-                break 'outer;
-            } else if line.contains("Exiting...") {
-                correct = false;
-                break 'outer;
-            }
-        };
-
-        if !correct {
-            let output = handle.wait_with_output()?;
-            let stderr = String::from_utf8_lossy(&output.stderr)
-                .to_string();
-
-            return Err(StoreError::MplayerFailed(stderr));
-        } else {
-            Ok(Mplayer { handle, pos, max_songs: files.len(), shuffle })
-        }
+        Ok(Mplayer { handle, pos, files: order, playing, paused: false, exited: false })
     }
 
     pub fn from_url(url: &str) -> Result<Self> {
@@ -85,7 +74,7 @@ impl Mplayer {
             return Err(StoreError::BinaryMissing("mplayer".into()));
         }
 
-        let mut handle = Command::new("/usr/bin/mplayer")
+        let handle = Command::new("/usr/bin/mplayer")
             .arg("-quiet")
             .arg(url)
             .stdin(Stdio::piped())
@@ -93,67 +82,149 @@ impl Mplayer {
             .stderr(Stdio::piped())
             .spawn()?;
 
-        let stdout = handle.stdout.as_mut().unwrap();
-        let stdout_reader = std::io::BufReader::with_capacity(8000 * 10000, stdout);
-        let stdout_lines = stdout_reader.lines();
+        let (handle, playing) = wait_for_playback(handle)?;
 
-        let mut correct = false;
-
-        'outer: for line in stdout_lines.into_iter().filter_map(|x| x.ok()) {
-            if line.contains("Starting playback...") {
-                correct = true;
-                break 'outer;
-            } else if line.contains("Exiting...") {
-                correct = false;
-                break 'outer;
-            }
-        };
+        Ok(Mplayer { handle, pos: 0, files: Vec::new(), playing, paused: false, exited: false })
+    }
+}
 
-        if !correct {
-            let output = handle.wait_with_output()?;
-            let stderr = String::from_utf8_lossy(&output.stderr)
-                .to_string();
+/// Wait until mplayer started to play, then keep reading its output in the background
+///
+/// The returned channel receives every file mplayer starts to play afterwards, which is the only
+/// way to notice that it advanced to the next file on its own.
+fn wait_for_playback(mut handle: Child) -> Result<(Child, Receiver<PathBuf>)> {
+    let stdout = handle.stdout.take().unwrap();
+    let mut stdout_lines = BufReader::with_capacity(8000 * 100, stdout).lines();
 
-            return Err(StoreError::MplayerFailed(stderr));
-        } else {
-            //drop(stdout_lines);
-            //drop(stdout_reader);
+    let mut correct = false;
 
-            Ok(Mplayer { handle, pos: 0, max_songs: 0, shuffle: false })
+    for line in stdout_lines.by_ref().map_while(|x| x.ok()) {
+        if line.contains("Starting playback...") {
+            correct = true;
+            break;
+        } else if line.contains("Exiting...") {
+            break;
         }
     }
-    pub fn has_next(&self) -> bool {
-        self.pos < self.max_songs-1
+
+    if !correct {
+        let output = handle.wait_with_output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr)
+            .to_string();
+
+        return Err(StoreError::MplayerFailed(stderr));
     }
 
-    pub fn next(&mut self) -> Result<()> {
+    // the thread ends with the output, once mplayer exits
+    let (sender, playing) = channel();
+    thread::spawn(move || {
+        for line in stdout_lines.map_while(|x| x.ok()) {
+            if let Some(file) = line.strip_prefix("Playing ").and_then(|x| x.strip_suffix('.')) {
+                if sender.send(PathBuf::from(file)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok((handle, playing))
+}
+
+impl Player for Mplayer {
+    fn next(&mut self) -> Result<()> {
         self.pos += 1;
-        self.handle.stdin.as_mut().unwrap().write(b">")?;
+        self.handle.stdin.as_mut().unwrap().write_all(b">")?;
+
+        Ok(())
+    }
 
-        dbg!(&self.pos);
+    fn prev(&mut self) -> Result<()> {
+        if self.pos == 0 {
+            return Err(StoreError::ReachedBeginningOfPlaylist);
+        }
+
+        self.pos -= 1;
+        self.handle.stdin.as_mut().unwrap().write_all(b"<")?;
 
         Ok(())
     }
 
-    pub fn has_prev(&self) -> bool {
-        self.pos > 0
+    /// Seeking is not supported, mplayer would need to run in slave mode for this
+    fn seek(&mut self, _: i64) -> Result<()> {
+        Ok(())
     }
 
-    pub fn prev(&mut self) -> Result<()> {
-        self.pos -= 1;
-        self.handle.stdin.as_mut().unwrap().write(b"<")?;
-        dbg!(&self.pos);
+    fn pause(&mut self) -> Result<()> {
+        if !self.paused {
+            self.paused = true;
+            self.handle.stdin.as_mut().unwrap().write_all(b"p")?;
+        }
 
         Ok(())
     }
 
-    pub fn is_shuffled(&self) -> bool {
-        self.shuffle
+    fn resume(&mut self) -> Result<()> {
+        if self.paused {
+            self.paused = false;
+            self.handle.stdin.as_mut().unwrap().write_all(b"p")?;
+        }
+
+        Ok(())
+    }
+
+    /// Mplayer plays with the volume of the mixer, which is not touched
+    fn set_volume(&mut self, _: u8) -> Result<()> {
+        Ok(())
     }
 
-    pub fn current_pos(&self) -> usize {
+    fn current_pos(&self) -> usize {
         self.pos
     }
+
+    fn current_file(&self) -> Option<PathBuf> {
+        self.files.get(self.pos).cloned()
+    }
+
+    /// Mplayer does not report its progress, so a resumed track always starts from the beginning
+    fn elapsed(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    /// Track changes are only known for playlists in order, as mplayer names the played file
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        // files played after a jump are already accounted for, only the following one is new
+        while let Ok(file) = self.playing.try_recv() {
+            if self.files.get(self.pos + 1) == Some(&file) {
+                self.pos += 1;
+
+                return Some(PlayerEvent::TrackChanged(self.pos));
+            }
+        }
+
+        match self.handle.try_wait() {
+            Ok(Some(_)) if !self.exited => {
+                self.exited = true;
+                Some(PlayerEvent::Finished)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Play music with an external mplayer process
+///
+/// Mplayer is controlled by writing keyboard shortcuts to its stdin, therefore we know little
+/// about the actual playback state. It is the only backend able to play radio streams.
+pub struct MplayerBackend;
+
+impl AudioBackend for MplayerBackend {
+    fn from_list(&self, files: &[PathBuf], shuffle: bool, position: Option<(usize, usize)>) -> Result<Box<dyn Player>> {
+        Ok(Box::new(Mplayer::from_list(files, shuffle, position)?))
+    }
+
+    fn from_url(&self, url: &str) -> Result<Box<dyn Player>> {
+        Ok(Box::new(Mplayer::from_url(url)?))
+    }
 }
 
 impl Drop for Mplayer {
@@ -164,3 +235,38 @@ impl Drop for Mplayer {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_played_files() {
+        // a shell stands in for mplayer, it plays the second file on its own and ignores the keys
+        let handle = Command::new("sh")
+            .args(["-c", "echo Playing /m/a.wav.; echo Starting playback...; echo Playing /m/b.wav.; cat > /dev/null"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn().unwrap();
+        let (handle, playing) = wait_for_playback(handle).unwrap();
+        let files = vec![PathBuf::from("/m/a.wav"), PathBuf::from("/m/b.wav")];
+        let mut player = Mplayer { handle, pos: 0, files, playing, paused: false, exited: false };
+
+        let start = Instant::now();
+        let event = loop {
+            if let Some(event) = player.poll_event() {
+                break event;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "track change never noticed");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(event, PlayerEvent::TrackChanged(1));
+        assert_eq!(player.current_file(), Some(PathBuf::from("/m/b.wav")));
+
+        player.prev().unwrap();
+        assert_eq!(player.current_pos(), 0);
+        assert!(matches!(player.prev(), Err(StoreError::ReachedBeginningOfPlaylist)));
+        assert_eq!(player.current_pos(), 0);
+    }
+}
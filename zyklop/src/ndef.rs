@@ -0,0 +1,305 @@
+use std::convert::TryInto;
+
+/// Scheme of the URIs written to NTAG stickers, followed by the playlist name
+const PLAYLIST_URI: &str = "odysseus://playlist/";
+
+/// TLV blocks in the user memory of a NFC Forum Type 2 tag
+const TLV_NULL: u8 = 0x00;
+const TLV_NDEF: u8 = 0x03;
+const TLV_TERMINATOR: u8 = 0xfe;
+
+/// Record header flags
+const MESSAGE_BEGIN: u8 = 0x80;
+const MESSAGE_END: u8 = 0x40;
+const SHORT_RECORD: u8 = 0x10;
+const ID_LENGTH: u8 = 0x08;
+const TNF_WELL_KNOWN: u8 = 0x01;
+
+/// Return the URI of a playlist, with the name percent encoded
+pub fn playlist_uri(name: &str) -> String {
+    let mut uri = PLAYLIST_URI.to_string();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    uri
+}
+
+/// Return the playlist name of a URI, if it is a playlist URI
+pub fn playlist_from_uri(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix(PLAYLIST_URI)?.as_bytes();
+
+    let mut name = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok()?;
+            name.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            name.push(encoded[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(name).ok().filter(|x| !x.is_empty())
+}
+
+/// Encode a NDEF message with a single URI record, wrapped in TLV blocks
+///
+/// The result is written to the user memory of a tag, starting at page 4.
+pub fn encode_uri(uri: &str) -> Vec<u8> {
+    // prefix code zero, the URI is stored completely
+    let mut payload = vec![0x00];
+    payload.extend_from_slice(uri.as_bytes());
+
+    let mut record = Vec::new();
+    if payload.len() < 256 {
+        record.push(MESSAGE_BEGIN | MESSAGE_END | SHORT_RECORD | TNF_WELL_KNOWN);
+        record.push(1);
+        record.push(payload.len() as u8);
+    } else {
+        record.push(MESSAGE_BEGIN | MESSAGE_END | TNF_WELL_KNOWN);
+        record.push(1);
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    }
+    record.push(b'U');
+    record.extend_from_slice(&payload);
+
+    let mut tlv = vec![TLV_NDEF];
+    if record.len() < 0xff {
+        tlv.push(record.len() as u8);
+    } else {
+        tlv.push(0xff);
+        tlv.extend_from_slice(&(record.len() as u16).to_be_bytes());
+    }
+    tlv.extend_from_slice(&record);
+    tlv.push(TLV_TERMINATOR);
+
+    tlv
+}
+
+/// Return the length of the TLV blocks at the start of the user memory, if they are complete
+///
+/// This tells the reader how many pages it has to read.
+pub fn tlv_length(data: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    loop {
+        match *data.get(i)? {
+            TLV_NULL => i += 1,
+            TLV_TERMINATOR => return Some(i + 1),
+            _ => {
+                let (length, header) = tlv_header(&data[i..])?;
+                i += header + length;
+            }
+        }
+    }
+}
+
+/// Find the first URI record in the TLV blocks of the user memory
+pub fn decode_uri(data: &[u8]) -> Option<String> {
+    let mut i = 0;
+    loop {
+        match *data.get(i)? {
+            TLV_NULL => i += 1,
+            TLV_TERMINATOR => return None,
+            tag => {
+                let (length, header) = tlv_header(&data[i..])?;
+                if tag == TLV_NDEF {
+                    return find_uri(data.get(i + header..i + header + length)?);
+                }
+
+                i += header + length;
+            }
+        }
+    }
+}
+
+/// Return the length of a TLV block and the size of its header
+fn tlv_header(data: &[u8]) -> Option<(usize, usize)> {
+    match *data.get(1)? {
+        0xff => Some((u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) as usize, 4)),
+        length => Some((length as usize, 2)),
+    }
+}
+
+/// Find the first URI record of a NDEF message
+fn find_uri(message: &[u8]) -> Option<String> {
+    let mut i = 0;
+    while i < message.len() {
+        let header = message[i];
+        let type_length = *message.get(i + 1)? as usize;
+        i += 2;
+
+        let payload_length = if header & SHORT_RECORD != 0 {
+            i += 1;
+            *message.get(i - 1)? as usize
+        } else {
+            i += 4;
+            u32::from_be_bytes(message.get(i - 4..i)?.try_into().ok()?) as usize
+        };
+
+        let id_length = if header & ID_LENGTH != 0 {
+            i += 1;
+            *message.get(i - 1)? as usize
+        } else {
+            0
+        };
+
+        let kind = message.get(i..i + type_length)?;
+        let payload = message.get(i + type_length + id_length..i + type_length + id_length + payload_length)?;
+        i += type_length + id_length + payload_length;
+
+        if header & 0x07 == TNF_WELL_KNOWN && kind == b"U" {
+            let (prefix, rest) = payload.split_first()?;
+            let rest = std::str::from_utf8(rest).ok()?;
+
+            return Some(format!("{}{}", uri_prefix(*prefix)?, rest));
+        }
+
+        if header & MESSAGE_END != 0 {
+            break;
+        }
+    }
+
+    None
+}
+
+/// Abbreviations of the URI record type, only the common ones are supported
+fn uri_prefix(code: u8) -> Option<&'static str> {
+    match code {
+        0x00 => Some(""),
+        0x01 => Some("http://www."),
+        0x02 => Some("https://www."),
+        0x03 => Some("http://"),
+        0x04 => Some("https://"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TLV blocks of a message with a single short record
+    fn message(header: u8, kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![header, kind.len() as u8, payload.len() as u8];
+        record.extend_from_slice(kind);
+        record.extend_from_slice(payload);
+
+        let mut data = vec![TLV_NDEF, record.len() as u8];
+        data.extend(record);
+        data.push(TLV_TERMINATOR);
+
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let uri = playlist_uri("Die drei ???");
+        let data = encode_uri(&uri);
+
+        assert_eq!(data[..5], [TLV_NDEF, data.len() as u8 - 3, 0xd1, 1, uri.len() as u8 + 1]);
+        assert_eq!(decode_uri(&data).as_deref(), Some(uri.as_str()));
+        assert_eq!(playlist_from_uri(&uri).as_deref(), Some("Die drei ???"));
+    }
+
+    #[test]
+    fn percent_encoding() {
+        assert_eq!(playlist_uri("Bibi & Tina"), "odysseus://playlist/Bibi%20%26%20Tina");
+        assert_eq!(playlist_uri("Märchen"), "odysseus://playlist/M%C3%A4rchen");
+        assert_eq!(playlist_uri("a-b_c.d~e"), "odysseus://playlist/a-b_c.d~e");
+
+        assert_eq!(playlist_from_uri("odysseus://playlist/M%c3%a4rchen").as_deref(), Some("Märchen"));
+        assert_eq!(playlist_from_uri("odysseus://playlist/a b").as_deref(), Some("a b"));
+    }
+
+    #[test]
+    fn reject_invalid_uris() {
+        assert_eq!(playlist_from_uri("https://example.com/book"), None);
+        assert_eq!(playlist_from_uri("odysseus://playlist/"), None);
+        assert_eq!(playlist_from_uri("odysseus://playlist/a%2"), None);
+        assert_eq!(playlist_from_uri("odysseus://playlist/a%zz"), None);
+        assert_eq!(playlist_from_uri("odysseus://playlist/%FF"), None);
+    }
+
+    #[test]
+    fn tlv_length_of_user_memory() {
+        let data = encode_uri("odysseus://playlist/book");
+        assert_eq!(tlv_length(&data), Some(data.len()));
+
+        // padding and other blocks before the message are counted, the rest of the memory not
+        let mut memory = vec![TLV_NULL, TLV_NULL, 0x01, 3, 0xa0, 0x0c, 0x34];
+        memory.extend_from_slice(&data);
+        memory.extend_from_slice(&[0; 16]);
+        assert_eq!(tlv_length(&memory), Some(7 + data.len()));
+
+        // the reader has to read more pages
+        assert_eq!(tlv_length(&data[..data.len() - 1]), None);
+        assert_eq!(tlv_length(&[TLV_NDEF]), None);
+        assert_eq!(tlv_length(&[TLV_TERMINATOR]), Some(1));
+    }
+
+    #[test]
+    fn long_records() {
+        // a short record, which is too long for a single byte TLV length
+        let uri = format!("odysseus://playlist/{}", "a".repeat(230));
+        let data = encode_uri(&uri);
+        assert_eq!(data[..4], [TLV_NDEF, 0xff, 0, 0xff]);
+        assert_eq!(data[4] & SHORT_RECORD, SHORT_RECORD);
+        assert_eq!(tlv_length(&data), Some(data.len()));
+        assert_eq!(decode_uri(&data).as_deref(), Some(uri.as_str()));
+
+        // a payload of 256 bytes needs a long record
+        let uri = format!("odysseus://playlist/{}", "b".repeat(235));
+        let data = encode_uri(&uri);
+        assert_eq!(data[..5], [TLV_NDEF, 0xff, 1, 7, MESSAGE_BEGIN | MESSAGE_END | TNF_WELL_KNOWN]);
+        assert_eq!(data[6..10], 256u32.to_be_bytes());
+        assert_eq!(tlv_length(&data), Some(data.len()));
+        assert_eq!(decode_uri(&data).as_deref(), Some(uri.as_str()));
+    }
+
+    #[test]
+    fn foreign_records() {
+        // prefixes written by phones are expanded
+        let data = message(0xd1, b"U", b"\x04example.com");
+        assert_eq!(decode_uri(&data).as_deref(), Some("https://example.com"));
+
+        // a text record before the URI is skipped
+        let mut record = vec![MESSAGE_BEGIN | SHORT_RECORD | TNF_WELL_KNOWN, 1, 3, b'T', 0x02, b'e', b'n'];
+        record.extend_from_slice(&[MESSAGE_END | SHORT_RECORD | TNF_WELL_KNOWN | ID_LENGTH, 1, 2, 1, b'U', b'x', 0x03, b'a']);
+        let mut data = vec![TLV_NDEF, record.len() as u8];
+        data.extend(record);
+        assert_eq!(decode_uri(&data).as_deref(), Some("http://a"));
+
+        assert_eq!(decode_uri(&message(0xd1, b"T", b"\x02enbook")), None);
+        assert_eq!(decode_uri(&message(0xd2, b"U", b"\x00odysseus://playlist/book")), None);
+    }
+
+    #[test]
+    fn reject_malformed_data() {
+        assert_eq!(decode_uri(&[]), None);
+        assert_eq!(decode_uri(&[TLV_NULL, TLV_TERMINATOR]), None);
+        assert_eq!(decode_uri(&[0; 16]), None);
+
+        // unknown prefix code
+        assert_eq!(decode_uri(&message(0xd1, b"U", b"\x23book")), None);
+
+        // invalid UTF-8
+        assert_eq!(decode_uri(&message(0xd1, b"U", b"\x00\xff\xfe")), None);
+
+        // lengths beyond the data
+        let mut data = encode_uri("odysseus://playlist/book");
+        data[1] += 1;
+        assert_eq!(decode_uri(&data[..data.len() - 1]), None);
+
+        let mut data = encode_uri("odysseus://playlist/book");
+        data[4] += 1;
+        assert_eq!(decode_uri(&data), None);
+        assert_eq!(decode_uri(&[TLV_NDEF, 0xff, 0]), None);
+    }
+}
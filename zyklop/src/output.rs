@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use hex2::{Result, StoreError};
+
+/// Layout of interleaved samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spec {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl Spec {
+    /// Return the playback duration of a number of interleaved samples
+    pub fn duration(&self, samples: usize) -> Duration {
+        let frames = samples as f64 / self.channels.max(1) as f64;
+
+        Duration::from_secs_f64(frames / self.sample_rate.max(1) as f64)
+    }
+}
+
+/// Destination of decoded audio
+pub trait Output {
+    /// Play interleaved samples, blocking until the output accepted them
+    fn write(&mut self, samples: &[f32], spec: Spec) -> Result<()>;
+
+    /// Drop everything which is buffered but not played yet
+    fn clear(&mut self) {}
+}
+
+/// Which output the in-process engine should use
+#[derive(Debug, Clone)]
+pub enum OutputKind {
+    /// The default sound card
+    Device,
+    /// Discard all samples, but take as long as playing them would
+    Null,
+    /// Write raw 32 bit float samples, little endian and interleaved, to a file
+    File(PathBuf),
+}
+
+impl OutputKind {
+    pub fn open(&self) -> Result<Box<dyn Output>> {
+        match self {
+            OutputKind::Device => Ok(Box::new(DeviceOutput::new())),
+            OutputKind::Null => Ok(Box::new(NullOutput)),
+            OutputKind::File(path) => Ok(Box::new(FileOutput::new(path)?)),
+        }
+    }
+}
+
+/// Output for machines without sound card, like a CI box
+pub struct NullOutput;
+
+impl Output for NullOutput {
+    fn write(&mut self, samples: &[f32], spec: Spec) -> Result<()> {
+        thread::sleep(spec.duration(samples.len()));
+
+        Ok(())
+    }
+}
+
+/// Output capturing the audio in a file, used for testing
+pub struct FileOutput {
+    file: BufWriter<File>,
+}
+
+impl FileOutput {
+    fn new(path: &Path) -> Result<FileOutput> {
+        Ok(FileOutput { file: BufWriter::new(File::create(path)?) })
+    }
+}
+
+impl Output for FileOutput {
+    fn write(&mut self, samples: &[f32], _: Spec) -> Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FileOutput {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.flush() {
+            eprintln!("could not flush audio file: {:?}", err);
+        }
+    }
+}
+
+/// Samples waiting for the sound card, shared with the callback of cpal
+type Buffer = Arc<(Mutex<VecDeque<f32>>, Condvar)>;
+
+/// How long the sound card may stop taking samples, before the playback fails
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Output to the default sound card with cpal
+///
+/// The stream is opened lazily and reopened whenever the sample layout changes.
+pub struct DeviceOutput {
+    stream: Option<(cpal::Stream, Spec)>,
+    buffer: Buffer,
+    /// Set by cpal when the stream failed, it is reopened with the next samples
+    failed: Arc<AtomicBool>,
+}
+
+impl DeviceOutput {
+    fn new() -> DeviceOutput {
+        DeviceOutput {
+            stream: None,
+            buffer: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            failed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn open_stream(&self, spec: Spec) -> Result<cpal::Stream> {
+        let device = cpal::default_host().default_output_device()
+            .ok_or_else(|| StoreError::PlaybackFailed("no output device found".into()))?;
+
+        let config = cpal::StreamConfig {
+            channels: spec.channels,
+            sample_rate: cpal::SampleRate(spec.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+
+        let buffer = self.buffer.clone();
+        let failed = self.failed.clone();
+        let stream = device.build_output_stream(&config, move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let (samples, cond) = &*buffer;
+            let mut samples = samples.lock().unwrap();
+
+            // play silence if the decoder does not keep up
+            for sample in data.iter_mut() {
+                *sample = samples.pop_front().unwrap_or(0.0);
+            }
+
+            cond.notify_all();
+        }, move |err| {
+            eprintln!("audio stream failed: {:?}", err);
+            failed.store(true, Ordering::SeqCst);
+        })
+            .map_err(|err| StoreError::PlaybackFailed(err.to_string()))?;
+
+        stream.play().map_err(|err| StoreError::PlaybackFailed(err.to_string()))?;
+
+        Ok(stream)
+    }
+}
+
+impl Output for DeviceOutput {
+    fn write(&mut self, samples: &[f32], spec: Spec) -> Result<()> {
+        if self.failed.swap(false, Ordering::SeqCst) || self.stream.as_ref().map(|x| x.1) != Some(spec) {
+            // the old stream and its buffered samples are dropped
+            self.stream = None;
+            self.clear();
+            self.stream = Some((self.open_stream(spec)?, spec));
+        }
+
+        // keep a quarter second buffered
+        let limit = (spec.sample_rate as usize * spec.channels as usize / 4).max(samples.len());
+
+        let (buffer, cond) = &*self.buffer;
+        let mut buffer = buffer.lock().unwrap();
+        let deadline = Instant::now() + spec.duration(limit) + STALL_TIMEOUT;
+        while buffer.len() + samples.len() > limit {
+            // never wait forever for a callback, which does not come anymore
+            if self.failed.load(Ordering::SeqCst) {
+                return Err(StoreError::PlaybackFailed("audio stream failed".into()));
+            }
+            if Instant::now() > deadline {
+                return Err(StoreError::PlaybackFailed("sound card stopped playing".into()));
+            }
+
+            buffer = cond.wait_timeout(buffer, Duration::from_millis(100)).unwrap().0;
+        }
+
+        buffer.extend(samples);
+
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.buffer.0.lock().unwrap().clear();
+    }
+}
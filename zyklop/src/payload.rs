@@ -0,0 +1,154 @@
+/// Marks a block written by a Zyklop
+const MAGIC: [u8; 3] = *b"ZYK";
+
+/// Version of the block layout, incremented when the layout changes
+const VERSION: u8 = 1;
+
+/// Size of a block of a MIFARE Classic card
+pub const BLOCK_SIZE: usize = 16;
+
+/// Encode a card id into the content of block 8
+///
+/// The block is laid out as follows:
+///
+/// ```text
+/// 0..3    magic "ZYK"
+/// 3       version
+/// 4..8    card id, big endian
+/// 8..12   reserved, zero
+/// 12..16  CRC-32 of bytes 0..12, big endian
+/// ```
+pub fn encode(id: u32) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[0..3].copy_from_slice(&MAGIC);
+    block[3] = VERSION;
+    block[4..8].copy_from_slice(&id.to_be_bytes());
+
+    let crc = crc32(&block[0..12]);
+    block[12..16].copy_from_slice(&crc.to_be_bytes());
+
+    block
+}
+
+/// Decode the card id from the content of block 8
+///
+/// Returns `None` for blank cards, cards written by something else and corrupted blocks. Cards
+/// programmed before the versioned layout only carry the id in the first four bytes, they are
+/// still accepted if the rest of the block is empty. Back then only the lowest byte of the id
+/// made it onto the card, so a legacy id of a multiple of 256 can't be told apart from a blank
+/// card, such cards have to be programmed again.
+pub fn decode(block: &[u8]) -> Option<u32> {
+    if block.len() < BLOCK_SIZE {
+        return None;
+    }
+
+    let id = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+
+    if block[0..3] == MAGIC {
+        let crc = u32::from_be_bytes([block[12], block[13], block[14], block[15]]);
+        if block[3] != VERSION || crc != crc32(&block[0..12]) {
+            return None;
+        }
+
+        return Some(id);
+    }
+
+    let legacy = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+    if legacy != 0 && block[4..BLOCK_SIZE].iter().all(|x| *x == 0) {
+        return Some(legacy);
+    }
+
+    None
+}
+
+/// CRC-32 as used by zlib and Ethernet
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for id in [0, 1, 255, 256, 0x1234_5678, u32::MAX] {
+            assert_eq!(decode(&encode(id)), Some(id));
+        }
+    }
+
+    #[test]
+    fn id_is_big_endian() {
+        let block = encode(0x0102_0304);
+
+        assert_eq!(block[4..8], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn blank_card_is_unprogrammed() {
+        assert_eq!(decode(&[0u8; BLOCK_SIZE]), None);
+        assert_eq!(decode(&[0xffu8; BLOCK_SIZE]), None);
+    }
+
+    #[test]
+    fn foreign_data_is_unprogrammed() {
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(b"Hello, world 123");
+
+        assert_eq!(decode(&block), None);
+    }
+
+    #[test]
+    fn corrupted_block_is_rejected() {
+        let mut block = encode(42);
+        block[7] ^= 0x01;
+
+        assert_eq!(decode(&block), None);
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut block = encode(42);
+        block[3] = VERSION + 1;
+        let crc = crc32(&block[0..12]);
+        block[12..16].copy_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(decode(&block), None);
+    }
+
+    /// Block as written before the versioned layout, which kept only the lowest byte of the id
+    fn legacy(id: u32) -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[3] = (id & 0xff) as u8;
+
+        block
+    }
+
+    #[test]
+    fn legacy_cards_are_accepted() {
+        assert_eq!(decode(&legacy(7)), Some(7));
+        assert_eq!(decode(&legacy(255)), Some(255));
+
+        // larger ids were truncated when written, they read back like they always did
+        assert_eq!(decode(&legacy(300)), Some(44));
+        assert_eq!(decode(&legacy(256)), None);
+    }
+
+    #[test]
+    fn short_block_is_rejected() {
+        assert_eq!(decode(&encode(7)[..8]), None);
+    }
+}
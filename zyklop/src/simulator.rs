@@ -0,0 +1,276 @@
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::events::{Card, CardIdentity, CardKind, Event, InputSource};
+use crate::gesture::Gesture;
+
+/// Single line of a simulated input session
+///
+/// The syntax is the same for the stdin simulator and scripts:
+///
+/// ```text
+/// card 3            # put a MIFARE Classic card with id 3 on the reader, its UID is the id in hex
+/// card 3 04a2b3c4   # put card with id 3 and UID 04a2b3c4 on the reader
+/// tag 04a2b3c4      # put an empty NTAG sticker, or a figurine, on the reader
+/// tag 04a2b3c4 Kids # put a NTAG sticker naming the playlist `Kids` on the reader
+/// lost              # remove the card
+/// button 0          # press the left (0), middle (1) or right (2) button shortly
+/// long 2            # start a long press
+/// hold 2 1500       # continue a long press, which is held for 1500 milliseconds
+/// double 1          # press a button twice
+/// chord 0 2         # press several buttons at once
+/// power pressed     # press or release the power button
+/// wait 500          # do nothing for 500 milliseconds
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Event(Event),
+    Wait(Duration),
+}
+
+impl Action {
+    /// Parse a line, returns `None` for empty lines and comments
+    fn parse(line: &str) -> Result<Option<Action>> {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let words = line.split_whitespace().collect::<Vec<_>>();
+
+        let (command, arguments) = match words.split_first() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let mut arguments = arguments.iter();
+        let mut argument = || arguments.next().copied()
+            .ok_or_else(|| anyhow!("`{}` needs an argument", command));
+
+        let action = match *command {
+            "card" => {
+                let id = argument()?.parse()?;
+                let uid = match words.get(2) {
+                    Some(uid) => uid_argument(uid)?,
+                    None => format!("{:08x}", id),
+                };
+
+                Action::Event(Event::NewCard(Card { uid, kind: CardKind::Classic, identity: CardIdentity::Id(id) }))
+            },
+            "tag" => {
+                let uid = uid_argument(argument()?)?;
+                let identity = match words[2..].join(" ") {
+                    name if name.is_empty() => CardIdentity::Unknown,
+                    name => CardIdentity::Playlist(name),
+                };
+
+                Action::Event(Event::NewCard(Card { uid, kind: CardKind::Ultralight, identity }))
+            },
+            "lost" => Action::Event(Event::CardLost),
+            "button" => Action::Event(Event::Gesture(Gesture::ShortPress(button(argument()?)?))),
+            "long" => Action::Event(Event::Gesture(Gesture::LongPress(button(argument()?)?))),
+            "double" => Action::Event(Event::Gesture(Gesture::DoublePress(button(argument()?)?))),
+            "hold" => {
+                let button = button(argument()?)?;
+                let duration = Duration::from_millis(argument()?.parse()?);
+
+                Action::Event(Event::Gesture(Gesture::Hold(button, duration)))
+            },
+            "chord" => {
+                let mut buttons = words[1..].iter().map(|x| button(x)).collect::<Result<Vec<_>>>()?;
+                buttons.sort_unstable();
+                buttons.dedup();
+
+                if buttons.len() < 2 {
+                    return Err(anyhow!("a chord needs at least two buttons"));
+                }
+
+                Action::Event(Event::Gesture(Gesture::Chord(buttons)))
+            },
+            "power" => match argument()? {
+                "pressed" => Action::Event(Event::PowerButton(true)),
+                "released" => Action::Event(Event::PowerButton(false)),
+                state => return Err(anyhow!("power button can't be {}", state)),
+            },
+            "wait" => Action::Wait(Duration::from_millis(argument()?.parse()?)),
+            _ => return Err(anyhow!("unknown command `{}`", command)),
+        };
+
+        Ok(Some(action))
+    }
+}
+
+fn uid_argument(word: &str) -> Result<String> {
+    Ok(hex2::format_uid(&hex2::parse_uid(word)?))
+}
+
+fn button(word: &str) -> Result<u8> {
+    match word.parse()? {
+        button @ 0..=2 => Ok(button),
+        button => Err(anyhow!("there is no button {}", button)),
+    }
+}
+
+/// Play events of a script, for testing the Zyklop without hardware
+///
+/// Scripts are parsed completely before the first event, so that a typo does not stop a test
+/// halfway.
+pub struct ScriptInput {
+    actions: Vec<Action>,
+    next: usize,
+}
+
+impl ScriptInput {
+    pub fn new(source: &str) -> Result<ScriptInput> {
+        let actions = source.lines().enumerate()
+            .filter_map(|(i, line)| Action::parse(line)
+                .map_err(|err| anyhow!("line {} of script: {}", i + 1, err))
+                .transpose())
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ScriptInput { actions, next: 0 })
+    }
+
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<ScriptInput> {
+        ScriptInput::new(&fs::read_to_string(path)?)
+    }
+}
+
+impl InputSource for ScriptInput {
+    fn poll(&mut self) -> Result<Option<Event>> {
+        let action = match self.actions.get(self.next) {
+            Some(action) => action.clone(),
+            None => return Ok(None),
+        };
+
+        self.next += 1;
+
+        match action {
+            Action::Event(event) => Ok(Some(event)),
+            Action::Wait(duration) => {
+                thread::sleep(duration);
+                Ok(None)
+            }
+        }
+    }
+
+    fn write_card(&mut self, identity: CardIdentity) -> Result<()> {
+        println!("Card programmed with {:?}", identity);
+
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.next >= self.actions.len()
+    }
+}
+
+/// Read events typed on the terminal, for trying the Zyklop on a laptop
+///
+/// Invalid lines are reported and ignored. The session ends with stdin.
+pub struct StdinInput {
+    finished: bool,
+}
+
+impl StdinInput {
+    pub fn new() -> StdinInput {
+        println!("Simulating inputs, type `card <id> [uid]`, `tag <uid> [playlist]`, `lost`, `button|long|double <0-2>`, `hold <0-2> <ms>`, `chord <buttons>`, `power pressed|released` or `wait <ms>`");
+
+        StdinInput { finished: false }
+    }
+}
+
+impl InputSource for StdinInput {
+    fn poll(&mut self) -> Result<Option<Event>> {
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            self.finished = true;
+            return Ok(None);
+        }
+
+        match Action::parse(&line) {
+            Ok(Some(Action::Event(event))) => Ok(Some(event)),
+            Ok(Some(Action::Wait(duration))) => {
+                thread::sleep(duration);
+                Ok(None)
+            },
+            Ok(None) => Ok(None),
+            Err(err) => {
+                eprintln!("{}", err);
+                Ok(None)
+            }
+        }
+    }
+
+    fn write_card(&mut self, identity: CardIdentity) -> Result<()> {
+        println!("Card programmed with {:?}", identity);
+
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn event(line: &str) -> Event {
+        match Action::parse(line).unwrap() {
+            Some(Action::Event(event)) => event,
+            action => panic!("`{}` is {:?}", line, action),
+        }
+    }
+
+    #[test]
+    fn parse_cards() {
+        assert_eq!(event("card 3"), Event::NewCard(Card { uid: "00000003".into(), kind: CardKind::Classic, identity: CardIdentity::Id(3) }));
+        assert_eq!(event("card 3 04:A2:B3:C4"), Event::NewCard(Card { uid: "04a2b3c4".into(), kind: CardKind::Classic, identity: CardIdentity::Id(3) }));
+        assert_eq!(event("tag 04a2b3c4"), Event::NewCard(Card { uid: "04a2b3c4".into(), kind: CardKind::Ultralight, identity: CardIdentity::Unknown }));
+        assert_eq!(event("tag 04a2b3c4 Bed time  # comment"), Event::NewCard(Card { uid: "04a2b3c4".into(), kind: CardKind::Ultralight, identity: CardIdentity::Playlist("Bed time".into()) }));
+        assert_eq!(event("lost"), Event::CardLost);
+    }
+
+    #[test]
+    fn parse_buttons() {
+        assert_eq!(event("button 0"), Event::Gesture(Gesture::ShortPress(0)));
+        assert_eq!(event("long 2"), Event::Gesture(Gesture::LongPress(2)));
+        assert_eq!(event("double 1"), Event::Gesture(Gesture::DoublePress(1)));
+        assert_eq!(event("hold 2 1500"), Event::Gesture(Gesture::Hold(2, Duration::from_millis(1500))));
+        assert_eq!(event("chord 2 1 2"), Event::Gesture(Gesture::Chord(vec![1, 2])));
+        assert_eq!(event("power pressed"), Event::PowerButton(true));
+        assert_eq!(event("power released"), Event::PowerButton(false));
+
+        assert_eq!(Action::parse("wait 500").unwrap(), Some(Action::Wait(Duration::from_millis(500))));
+        assert_eq!(Action::parse("  # only a comment").unwrap(), None);
+        assert_eq!(Action::parse("").unwrap(), None);
+    }
+
+    #[test]
+    fn reject_invalid_lines() {
+        for line in ["jump 3", "card", "card x", "card 3 xyz", "tag", "button 3", "long", "hold 1", "chord 1 1", "power on", "wait soon"] {
+            assert!(Action::parse(line).is_err(), "`{}` was accepted", line);
+        }
+
+        let err = ScriptInput::new("card 3\n\nbutton 7\n").err().unwrap();
+        assert_eq!(err.to_string(), "line 3 of script: there is no button 7");
+    }
+
+    #[test]
+    fn play_script() {
+        let mut script = ScriptInput::new("card 3\nwait 50\n# pause\nlost\n").unwrap();
+
+        assert!(matches!(script.poll().unwrap(), Some(Event::NewCard(_))));
+        let start = Instant::now();
+        assert_eq!(script.poll().unwrap(), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(!script.is_finished());
+        assert_eq!(script.poll().unwrap(), Some(Event::CardLost));
+        assert!(script.is_finished());
+        assert_eq!(script.poll().unwrap(), None);
+    }
+}
@@ -1,15 +1,970 @@
-use hex2::Result;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use hex2::{Playlist, Playlists, StoreError};
+
+use crate::audio::PlayerEvent;
+use crate::config;
+use crate::events::{Card, CardIdentity, CardKind, Event};
+use crate::gesture::Gesture;
+use crate::led::{self, Color};
+
+/// Seconds skipped by every step of fast forward and rewind
+const SEEK_STEP: i64 = 10;
+
+/// Color of the LED ring while a sleep timer runs
+const SLEEP_COLOR: Color = Color(96, 0, 160, 255);
+
+/// Something the state machine reacts to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// Buttons or the card reader
+    Event(Event),
+    /// The running player
+    Player(PlayerEvent),
+    /// The control API
+    Command(Command),
+    /// Time passed without any other input
+    Tick(Instant),
+}
+
+/// Command of the control API
+///
+/// Commands act like the buttons and cards, but name playlists instead of choosing them by card
+/// or sample. Errors are reported as `Effect::Error`, like for the buttons.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Play(String),
+    Next,
+    Prev,
+    Pause,
+    Resume,
+    Stop,
+    Shuffle,
+    Program(String),
+    /// Set the volume in percent, it is capped like the buttons are
+    SetVolume(u8),
+}
+
+/// Progress of the running player, reported along with every input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub track: usize,
+    pub elapsed: Duration,
+}
+
+/// Running sleep timer of a playlist
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sleep {
+    /// When the time is up
+    pub at: Instant,
+    /// Wait for the end of the current track once the time is up, instead of fading out
+    pub finish_track: bool,
+    /// Volume set by the fade out so far, once it started
+    pub faded: Option<u8>,
+}
+
+/// What the Zyklop is doing right now
+///
+/// The player itself is not part of the state, it is owned by the driver executing the effects.
+#[derive(Debug, Clone)]
+pub enum State {
+    Idle,
+    Playing { playlist: Playlist, shuffled: bool, sleep: Option<Sleep> },
+    /// The player is halted, but still holds its position. A removed card pauses until the grace
+    /// period ends at `until`, putting it back in the meantime continues the playback.
+    Paused { playlist: Playlist, shuffled: bool, sleep: Option<Sleep>, until: Option<Instant> },
+    /// `card` is on the reader, but not assigned yet, it gets `card_id` if an id is written. A
+    /// sample of every playlist in `playlists` is played to choose from.
+    Programming { card_id: u32, card: Card, playlists: Vec<Playlist> },
+}
+
+/// Side effect of a transition, executed by the driver in order
+#[derive(Debug)]
+pub enum Effect {
+    /// Replace the running player with one playing a list of files
+    Play { files: Vec<PathBuf>, shuffle: bool, position: Option<(usize, usize)> },
+    /// Replace the running player with one playing a radio stream
+    PlayUrl(String),
+    /// Stop the running player
+    Stop,
+    Pause,
+    Resume,
+    Next,
+    Prev,
+    /// Jump forward or, if negative, backward within the current track
+    Seek(i64),
+    Led(led::State),
+    /// Write an identity to the card on the reader
+    WriteCard(CardIdentity),
+    SetPosition { playlist: String, track: usize, seconds: usize },
+    /// Assign a card to a playlist and commit the library
+    AssignCard { playlist: String, card_id: u32 },
+    /// Assign the UID of a card to a playlist and commit the library
+    AssignCardUid { playlist: String, uid: String },
+    /// Signal an error to the user, blinking red for a while
+    Error(StoreError, Duration),
+    /// Set the volume of the running and all following players, in percent
+    Volume(u8),
+    /// Show a volume on the LED ring for a moment, as share of the maximum volume
+    ShowVolume(u8, u8),
+    /// Switch the Zyklop off
+    Shutdown,
+    /// Quit right away, without the shutdown sequence
+    ForceQuit,
+}
+
+/// Transitions between the states of a Zyklop
+///
+/// The state machine knows the playlists of the library, but does not touch the store, the
+/// player or the hardware. Instead every transition returns a list of effects, which keeps the
+/// transitions free of side effects.
+pub struct StateMachine {
+    playlists: Playlists,
+    /// Whether new cards get an id written, instead of being known by their UID
+    write_ids: bool,
+    limits: config::Volume,
+    /// Current volume in percent, never above `limits.max`
+    volume: u8,
+    /// How long a removed card only pauses the playback
+    grace_period: Duration,
+    /// How long a sleep timer fades out
+    fade: Duration,
+    idle_shutdown: Option<Duration>,
+    /// Since when the Zyklop is idle without any input
+    idle_since: Option<Instant>,
+    /// Since when the power button is held
+    power_pressed: Option<Instant>,
+    /// How long the power button is held to quit at once
+    force_quit: Duration,
+}
+
+impl StateMachine {
+    pub fn new(playlists: Vec<Playlist>, config: &config::Config) -> StateMachine {
+        StateMachine {
+            playlists: Playlists { playlists },
+            write_ids: config.reader.write_ids,
+            limits: config.volume,
+            volume: config.volume.default.min(config.volume.max),
+            grace_period: config.playback.grace_period(),
+            fade: config.timers.fade(),
+            idle_shutdown: config.timers.idle_shutdown(),
+            idle_since: None,
+            power_pressed: None,
+            force_quit: config.power.force_quit(),
+        }
+    }
+
+    /// Return the next state and the effects leading there
+    ///
+    /// `progress` is the progress of the running player, if there is one.
+    pub fn handle(&mut self, state: State, input: Input, progress: Option<Progress>) -> (State, Vec<Effect>) {
+        let mut effects = Vec::new();
+
+        // every input but the passing time keeps the Zyklop awake
+        if !matches!(input, Input::Tick(_)) {
+            self.idle_since = None;
+        }
+
+        let event = match input {
+            Input::Event(event) => event,
+            // reset the playlist, once the last track finished
+            Input::Player(PlayerEvent::Finished) => match state {
+                State::Playing { playlist, .. } => {
+                    effects.push(self.set_position(&playlist.name, 0, 0));
+                    effects.push(Effect::Stop);
+                    effects.push(Effect::Led(led_state(&State::Idle)));
+
+                    return (State::Idle, effects);
+                },
+                state => return (state, effects),
+            },
+            // a sleep timer waiting for the end of a track stops before the next one
+            Input::Player(PlayerEvent::TrackChanged(_)) => match state {
+                State::Playing { sleep: Some(sleep), .. } if sleep.finish_track && Instant::now() >= sleep.at => {
+                    let state = self.stop(state, progress, &mut effects);
+                    effects.push(Effect::Led(led_state(&state)));
+
+                    return (state, effects);
+                },
+                state => return (state, effects),
+            },
+            Input::Command(command) => return self.command(state, command, progress),
+            Input::Tick(now) => return self.tick(state, now, progress),
+        };
+
+        let track = progress.map(|x| x.track).unwrap_or(0);
+
+        let state = match (event, state) {
+            (Event::NewCard(card), State::Paused { playlist, shuffled, sleep, .. }) => {
+                // the same card continues right away, another one replaces the paused playlist
+                if self.playlist_of_card(&card).map(|x| x.name == playlist.name).unwrap_or(false) {
+                    effects.push(Effect::Resume);
+
+                    State::Playing { playlist, shuffled, sleep }
+                } else {
+                    let state = self.stop(State::Paused { playlist, shuffled, sleep, until: None }, progress, &mut effects);
+                    let (state, new_effects) = self.handle(state, Input::Event(Event::NewCard(card)), None);
+                    effects.extend(new_effects);
+
+                    return (state, effects);
+                }
+            },
+            (Event::NewCard(card), State::Idle) => {
+                if let Some(pl) = self.playlist_of_card(&card) {
+                    self.start(pl, &mut effects)
+                } else {
+                    // all playlists without a card, with the first song of each as sample
+                    let card_id = self.playlists.next_card_id();
+                    let playlists = self.playlists.playlists.iter()
+                        .filter(|x| x.card_id.is_none() && x.card_uid.is_none() && !x.files.is_empty())
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+                    let files = playlists.iter().map(|x| x.files[0].clone()).collect();
+                    effects.push(Effect::Play { files, shuffle: false, position: None });
+
+                    State::Programming { card_id, card, playlists }
+                }
+            },
+            // the card might be put back right away, so the player is kept for a while
+            (Event::CardLost, State::Playing { playlist, shuffled, sleep }) if !self.grace_period.is_zero() => {
+                effects.push(Effect::Pause);
+
+                // the Zyklop might be switched off before the card comes back
+                let state = State::Paused { playlist, shuffled, sleep, until: Some(Instant::now() + self.grace_period) };
+                self.save_position(&state, progress, &mut effects);
+
+                state
+            },
+            (Event::CardLost, State::Paused { playlist, shuffled, sleep, until: None }) if !self.grace_period.is_zero() => {
+                State::Paused { playlist, shuffled, sleep, until: Some(Instant::now() + self.grace_period) }
+            },
+            (Event::CardLost, state) => self.stop(state, progress, &mut effects),
+            // a long press of the middle button pauses and continues
+            (Event::Gesture(Gesture::LongPress(1)), State::Playing { playlist, shuffled, sleep }) => {
+                effects.push(Effect::Pause);
+
+                let state = State::Paused { playlist, shuffled, sleep, until: None };
+                self.save_position(&state, progress, &mut effects);
+
+                state
+            },
+            (Event::Gesture(Gesture::LongPress(1)), State::Paused { playlist, shuffled, sleep, .. }) => {
+                effects.push(Effect::Resume);
+
+                State::Playing { playlist, shuffled, sleep }
+            },
+            (Event::Gesture(Gesture::ShortPress(2)), state @ State::Playing { .. }) |
+            (Event::Gesture(Gesture::ShortPress(2)), state @ State::Programming { .. }) => {
+                if track + 1 < track_count(&state) {
+                    effects.push(Effect::Next);
+                } else {
+                    effects.push(Effect::Error(StoreError::ReachedEndOfPlaylist, Duration::from_millis(2000)));
+                }
+
+                state
+            },
+            (Event::Gesture(Gesture::ShortPress(0)), state @ State::Playing { .. }) |
+            (Event::Gesture(Gesture::ShortPress(0)), state @ State::Programming { .. }) => {
+                if track > 0 {
+                    effects.push(Effect::Prev);
+                } else {
+                    effects.push(Effect::Error(StoreError::ReachedBeginningOfPlaylist, Duration::from_millis(2000)));
+                }
+
+                state
+            },
+            // holding next or previous winds through the track
+            (Event::Gesture(Gesture::LongPress(button)), State::Playing { playlist, shuffled, sleep }) |
+            (Event::Gesture(Gesture::Hold(button, _)), State::Playing { playlist, shuffled, sleep })
+                if (button == 0 || button == 2) && playlist.radio_url.is_none() => {
+                effects.push(Effect::Seek(if button == 2 { SEEK_STEP } else { -SEEK_STEP }));
+
+                State::Playing { playlist, shuffled, sleep }
+            },
+            (Event::Gesture(Gesture::ShortPress(1)), State::Playing { playlist, shuffled, sleep }) => {
+                if playlist.allow_random {
+                    effects.push(Effect::Play { files: playlist.files.clone(), shuffle: !shuffled, position: None });
+
+                    State::Playing { playlist, shuffled: !shuffled, sleep }
+                } else {
+                    effects.push(Effect::Error(StoreError::RandomNotAllowed, Duration::from_millis(2000)));
+
+                    State::Playing { playlist, shuffled, sleep }
+                }
+            },
+            (Event::Gesture(Gesture::ShortPress(1)), State::Programming { card_id, card, playlists }) if track < playlists.len() => {
+                self.assign(card_id, card, playlists[track].clone(), &mut effects)
+            },
+            // middle and right button together are louder, middle and left quieter
+            (Event::Gesture(Gesture::Chord(buttons)), state) if buttons == [1, 2] || buttons == [0, 1] => {
+                let volume = if buttons == [1, 2] {
+                    self.volume.saturating_add(self.limits.step)
+                } else {
+                    self.volume.saturating_sub(self.limits.step)
+                };
+
+                effects.push(self.set_volume(volume));
+                effects.push(Effect::ShowVolume(self.volume, self.limits.max));
+
+                state
+            },
+            // the power button shuts down once released, holding it long enough quits before
+            (Event::PowerButton(true), state) => {
+                self.power_pressed = Some(Instant::now());
+
+                state
+            },
+            (Event::PowerButton(false), state) if self.power_pressed.take().is_some() => {
+                self.save_position(&state, progress, &mut effects);
+                effects.push(Effect::Shutdown);
+
+                State::Idle
+            },
+            (Event::WriteFailed(err), state) => {
+                effects.push(Effect::Error(StoreError::CardWriteFailed(err), Duration::from_millis(2000)));
+
+                state
+            },
+            (_, state) => state,
+        };
+
+        // update the LED to the new state
+        effects.push(Effect::Led(led_state(&state)));
+
+        (state, effects)
+    }
+
+    /// Handle a command of the control API
+    fn command(&mut self, state: State, command: Command, progress: Option<Progress>) -> (State, Vec<Effect>) {
+        let error = |state: State, err: StoreError| {
+            let effects = vec![Effect::Error(err, Duration::from_millis(2000)), Effect::Led(led_state(&state))];
+
+            (state, effects)
+        };
+
+        match (command, state) {
+            (Command::Play(name), state) => {
+                if !self.playlists.playlists.iter().any(|x| x.name == name) {
+                    return error(state, StoreError::PlaylistNotFound(name));
+                }
+
+                // leave the current playlist, this also stores its position
+                let mut effects = Vec::new();
+                self.stop(state, progress, &mut effects);
+                let playlist = self.playlists.playlists.iter().find(|x| x.name == name).cloned().unwrap();
+                let state = self.start(playlist, &mut effects);
+                effects.push(Effect::Led(led_state(&state)));
+
+                (state, effects)
+            },
+            (Command::Program(name), State::Programming { card_id, card, playlists }) => {
+                match playlists.iter().find(|x| x.name == name).cloned() {
+                    Some(playlist) => {
+                        let mut effects = Vec::new();
+                        let state = self.assign(card_id, card, playlist, &mut effects);
+                        effects.push(Effect::Led(led_state(&state)));
+
+                        (state, effects)
+                    },
+                    None => {
+                        // only playlists without a card are offered, like when choosing with the buttons
+                        let err = if self.playlists.playlists.iter().any(|x| x.name == name) {
+                            StoreError::PlaylistHasCard(name)
+                        } else {
+                            StoreError::PlaylistNotFound(name)
+                        };
+
+                        error(State::Programming { card_id, card, playlists }, err)
+                    },
+                }
+            },
+            (Command::Program(_), state) => error(state, StoreError::NoNewCard),
+            (Command::SetVolume(volume), state) => {
+                let effects = vec![
+                    self.set_volume(volume),
+                    Effect::ShowVolume(self.volume, self.limits.max),
+                    Effect::Led(led_state(&state)),
+                ];
+
+                (state, effects)
+            },
+            (Command::Stop, state) => {
+                let mut effects = Vec::new();
+                let state = self.stop(state, progress, &mut effects);
+                effects.push(Effect::Led(led_state(&state)));
+
+                (state, effects)
+            },
+            (Command::Pause, state @ State::Playing { .. }) | (Command::Resume, state @ State::Paused { .. }) => {
+                self.handle(state, Input::Event(Event::Gesture(Gesture::LongPress(1))), progress)
+            },
+            (Command::Pause, state @ State::Paused { .. }) | (Command::Resume, state @ State::Playing { .. }) => (state, Vec::new()),
+            (Command::Pause, state) | (Command::Resume, state) => error(state, StoreError::NothingPlaying),
+            (_, State::Idle) => error(State::Idle, StoreError::NothingPlaying),
+            (_, state @ State::Paused { .. }) => error(state, StoreError::PlaybackPaused),
+            (command, state) => {
+                let button = match command {
+                    Command::Prev => 0,
+                    Command::Shuffle => 1,
+                    _ => 2,
+                };
+
+                self.handle(state, Input::Event(Event::Gesture(Gesture::ShortPress(button))), progress)
+            },
+        }
+    }
+
+    /// Start playing a playlist
+    fn start(&mut self, pl: Playlist, effects: &mut Vec<Effect>) -> State {
+        effects.push(Effect::Led(led::State::Sine(playlist_color(&pl), 500.0)));
+        effects.push(self.set_volume(pl.volume.unwrap_or(self.limits.default)));
+
+        match &pl.radio_url {
+            Some(url) => effects.push(Effect::PlayUrl(url.clone())),
+            None => effects.push(Effect::Play { files: pl.files.clone(), shuffle: false, position: pl.position }),
+        }
+
+        let sleep = sleep_timer(&pl);
+
+        State::Playing { playlist: pl, shuffled: false, sleep }
+    }
+
+    /// React to the passing time: grace periods, sleep timers and the idle shutdown
+    fn tick(&mut self, state: State, now: Instant, progress: Option<Progress>) -> (State, Vec<Effect>) {
+        if self.power_pressed.map(|x| now - x >= self.force_quit).unwrap_or(false) {
+            self.power_pressed = None;
+
+            return (state, vec![Effect::ForceQuit]);
+        }
+
+        let mut effects = Vec::new();
+        let mut led = false;
+
+        let state = match state {
+            State::Paused { until: Some(until), .. } if now >= until => {
+                led = true;
+                self.stop(state, progress, &mut effects)
+            },
+            State::Paused { sleep: Some(sleep), .. } if now >= sleep.at => {
+                led = true;
+                self.stop(state, progress, &mut effects)
+            },
+            // fade out linearly, then stop like removing the card would
+            State::Playing { playlist, shuffled, sleep: Some(mut sleep) } if !sleep.finish_track && now >= sleep.at => {
+                let passed = now - sleep.at;
+
+                if passed >= self.fade {
+                    led = true;
+                    self.stop(State::Playing { playlist, shuffled, sleep: Some(sleep) }, progress, &mut effects)
+                } else {
+                    let volume = (self.volume as f32 * (1.0 - passed.as_secs_f32() / self.fade.as_secs_f32())) as u8;
+                    if sleep.faded != Some(volume) {
+                        led = sleep.faded.is_none();
+                        sleep.faded = Some(volume);
+                        effects.push(Effect::Volume(volume));
+                    }
+
+                    State::Playing { playlist, shuffled, sleep: Some(sleep) }
+                }
+            },
+            State::Idle => {
+                let since = *self.idle_since.get_or_insert(now);
+                if self.idle_shutdown.map(|x| now - since >= x).unwrap_or(false) {
+                    self.idle_since = None;
+                    effects.push(Effect::Shutdown);
+                }
+
+                State::Idle
+            },
+            state => state,
+        };
+
+        if led {
+            effects.push(Effect::Led(led_state(&state)));
+        }
+
+        (state, effects)
+    }
+
+    /// Stop the player for good and return to idle
+    fn stop(&mut self, state: State, progress: Option<Progress>, effects: &mut Vec<Effect>) -> State {
+        let faded = match &state {
+            State::Playing { sleep: Some(sleep), .. } | State::Paused { sleep: Some(sleep), .. } => sleep.faded.is_some(),
+            _ => false,
+        };
+
+        self.save_position(&state, progress, effects);
+        effects.push(Effect::Stop);
+
+        // the fade out only lowered the volume of the player, not the one chosen by the user
+        if faded {
+            effects.push(Effect::Volume(self.volume));
+        }
 
-/*impl State {AAA
-    pub fn new() -> Self {
         State::Idle
     }
 
-    pub fn process(&mut self, event: Event) -> Result<()> {
-        match event {
-            Event::NewCard(id) => {
+    /// Remember the track and the second, to resume audiobooks in the middle of a chapter
+    fn save_position(&mut self, state: &State, progress: Option<Progress>, effects: &mut Vec<Effect>) {
+        match (state, progress) {
+            (State::Playing { playlist, .. }, Some(progress)) | (State::Paused { playlist, .. }, Some(progress)) => {
+                effects.push(self.set_position(&playlist.name, progress.track, progress.elapsed.as_secs() as usize));
+            },
+            _ => {},
+        }
+    }
+
+    /// Return the playlist of a card
+    fn playlist_of_card(&self, card: &Card) -> Option<Playlist> {
+        // a mapped UID wins, the card might still carry an identity from an earlier assignment
+        let playlists = &self.playlists.playlists;
+
+        playlists.iter()
+            .find(|x| x.card_uid.as_ref() == Some(&card.uid))
+            .or_else(|| match &card.identity {
+                CardIdentity::Id(id) => playlists.iter().find(|x| x.card_id == Some(*id)),
+                CardIdentity::Playlist(name) => playlists.iter().find(|x| &x.name == name),
+                CardIdentity::Unknown => None,
+            })
+            .cloned()
+    }
+
+    /// Assign the card on the reader to a playlist and start playing it
+    fn assign(&mut self, card_id: u32, card: Card, mut playlist: Playlist, effects: &mut Vec<Effect>) -> State {
+        // MIFARE Classic cards get an id, stickers the UID and a URI naming the playlist
+        if self.write_ids && card.kind == CardKind::Classic {
+            playlist.card_id = Some(card_id);
+            effects.push(Effect::AssignCard { playlist: playlist.name.clone(), card_id });
+            effects.push(Effect::WriteCard(CardIdentity::Id(card_id)));
+        } else {
+            playlist.card_uid = Some(card.uid.clone());
+            effects.push(Effect::AssignCardUid { playlist: playlist.name.clone(), uid: card.uid });
+
+            if self.write_ids {
+                effects.push(Effect::WriteCard(CardIdentity::Playlist(playlist.name.clone())));
             }
         }
+
+        if let Some(pl) = self.playlists.playlists.iter_mut().find(|x| x.name == playlist.name) {
+            pl.card_id = playlist.card_id;
+            pl.card_uid = playlist.card_uid.clone();
+        }
+
+        effects.push(self.set_volume(playlist.volume.unwrap_or(self.limits.default)));
+        effects.push(Effect::Play { files: playlist.files.clone(), shuffle: false, position: None });
+
+        let sleep = sleep_timer(&playlist);
+
+        State::Playing { playlist, shuffled: false, sleep }
+    }
+
+    fn set_position(&mut self, name: &str, track: usize, seconds: usize) -> Effect {
+        if let Some(pl) = self.playlists.playlists.iter_mut().find(|x| x.name == name) {
+            pl.position = Some((track, seconds));
+        }
+
+        Effect::SetPosition { playlist: name.into(), track, seconds }
+    }
+
+    /// Change the volume, capped by the configured maximum
+    fn set_volume(&mut self, volume: u8) -> Effect {
+        self.volume = volume.min(self.limits.max);
+
+        Effect::Volume(self.volume)
+    }
+}
+
+/// Start the sleep timer of a playlist, if it has one
+fn sleep_timer(pl: &Playlist) -> Option<Sleep> {
+    pl.sleep_timer.map(|timer| Sleep {
+        at: Instant::now() + Duration::from_secs(timer.minutes as u64 * 60),
+        finish_track: timer.finish_track,
+        faded: None,
+    })
+}
+
+/// Number of tracks the player of a state can skip through
+fn track_count(state: &State) -> usize {
+    match state {
+        State::Idle => 0,
+        State::Playing { playlist, .. } | State::Paused { playlist, .. } => playlist.files.len(),
+        State::Programming { playlists, .. } => playlists.len(),
+    }
+}
+
+fn playlist_color(pl: &Playlist) -> Color {
+    if pl.radio_url.is_some() {
+        Color(0, 255, 255, 255)
+    } else {
+        Color(0, 0, 255, 255)
+    }
+}
+
+/// Return the color showing a volume, from green for silence to red for the maximum volume
+pub fn volume_color(volume: u8, max: u8) -> Color {
+    let share = (volume as u32 * 255 / max.max(1) as u32).min(255) as u8;
+
+    Color(share, 255 - share, 0, 255)
+}
+
+/// Return the LED state showing a state
+pub fn led_state(state: &State) -> led::State {
+    match state {
+        State::Idle => led::State::Continuous(Color(255, 255, 255, 255)),
+        // a running sleep timer shows as dark purple, breathing while fading out
+        State::Playing { sleep: Some(Sleep { faded: Some(_), .. }), .. } => led::State::Sine(SLEEP_COLOR, 1000.0),
+        State::Playing { sleep: Some(_), .. } => led::State::Continuous(SLEEP_COLOR),
+        State::Playing { playlist, .. } => led::State::Continuous(playlist_color(playlist)),
+        // breathing slowly, while waiting to continue
+        State::Paused { playlist, .. } => led::State::Sine(playlist_color(playlist), 2000.0),
+        State::Programming { .. } => led::State::Continuous(Color(255, 255, 0, 255)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex2::SleepTimer;
+
+    fn playlist(name: &str, files: &[&str]) -> Playlist {
+        Playlist { files: files.iter().map(PathBuf::from).collect(), ..Playlist::new(name) }
+    }
+
+    fn playlists() -> Vec<Playlist> {
+        vec![
+            Playlist { card_id: Some(0), ..playlist("book", &["1.mp3", "2.mp3"]) },
+            Playlist { card_uid: Some("04a2b3c4".into()), ..playlist("figure", &["a.mp3"]) },
+            playlist("free", &["x.mp3"]),
+            playlist("other", &["y.mp3"]),
+        ]
+    }
+
+    fn machine(config: impl FnOnce(&mut config::Config)) -> StateMachine {
+        let mut cfg = config::Config::default();
+        config(&mut cfg);
+
+        StateMachine::new(playlists(), &cfg)
+    }
+
+    fn classic(id: Option<u32>) -> Card {
+        let identity = id.map(CardIdentity::Id).unwrap_or(CardIdentity::Unknown);
+
+        Card { uid: "01020304".into(), kind: CardKind::Classic, identity }
+    }
+
+    fn sticker(uid: &str) -> Card {
+        Card { uid: uid.into(), kind: CardKind::Ultralight, identity: CardIdentity::Unknown }
+    }
+
+    fn event(machine: &mut StateMachine, state: State, event: Event) -> (State, Vec<Effect>) {
+        machine.handle(state, Input::Event(event), Some(Progress { track: 1, elapsed: Duration::from_secs(42) }))
+    }
+
+    fn playing(machine: &mut StateMachine, card: Card) -> State {
+        let (state, _) = event(machine, State::Idle, Event::NewCard(card));
+        assert!(matches!(state, State::Playing { .. }), "{:?}", state);
+
+        state
+    }
+
+    fn name(state: &State) -> Option<&str> {
+        match state {
+            State::Playing { playlist, .. } | State::Paused { playlist, .. } => Some(&playlist.name),
+            _ => None,
+        }
+    }
+
+    fn saved(effects: &[Effect], playlist: &str) -> bool {
+        effects.iter().any(|x| matches!(x, Effect::SetPosition { playlist: name, track: 1, seconds: 42 } if name == playlist))
+    }
+
+    #[test]
+    fn known_card_plays() {
+        let mut machine = machine(|_| {});
+
+        let (state, effects) = event(&mut machine, State::Idle, Event::NewCard(classic(Some(0))));
+        assert_eq!(name(&state), Some("book"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Play { files, shuffle: false, .. } if files.len() == 2)));
+
+        // a mapped UID is found without any identity on the card
+        let (state, _) = event(&mut machine, State::Idle, Event::NewCard(sticker("04a2b3c4")));
+        assert_eq!(name(&state), Some("figure"));
+    }
+
+    #[test]
+    fn program_classic_card() {
+        let mut machine = machine(|_| {});
+
+        // every playlist without a card is offered with a sample
+        let (state, effects) = event(&mut machine, State::Idle, Event::NewCard(classic(None)));
+        let offered = match &state {
+            State::Programming { card_id: 1, playlists, .. } => playlists.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            state => panic!("not programming: {:?}", state),
+        };
+        assert_eq!(offered, ["free", "other"]);
+        assert!(effects.iter().any(|x| matches!(x, Effect::Play { files, .. } if files.len() == 2)));
+
+        // the second sample is chosen
+        let (state, effects) = event(&mut machine, state, Event::Gesture(Gesture::ShortPress(1)));
+        assert_eq!(name(&state), Some("other"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::AssignCard { playlist, card_id: 1 } if playlist == "other")));
+        assert!(effects.iter().any(|x| matches!(x, Effect::WriteCard(CardIdentity::Id(1)))));
+
+        // the card is known from now on
+        let (state, _) = event(&mut machine, State::Idle, Event::NewCard(classic(Some(1))));
+        assert_eq!(name(&state), Some("other"));
+    }
+
+    #[test]
+    fn program_sticker() {
+        let mut machine = machine(|_| {});
+
+        let (state, _) = event(&mut machine, State::Idle, Event::NewCard(sticker("04aabbcc")));
+        let (state, effects) = event(&mut machine, state, Event::Gesture(Gesture::ShortPress(1)));
+        assert_eq!(name(&state), Some("other"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::AssignCardUid { playlist, uid } if playlist == "other" && uid == "04aabbcc")));
+        assert!(effects.iter().any(|x| matches!(x, Effect::WriteCard(CardIdentity::Playlist(name)) if name == "other")));
+
+        // without writing ids, a classic card is known by its UID as well
+        let mut machine = self::machine(|config| config.reader.write_ids = false);
+        let (state, _) = event(&mut machine, State::Idle, Event::NewCard(classic(None)));
+        let (_, effects) = event(&mut machine, state, Event::Gesture(Gesture::ShortPress(1)));
+        assert!(effects.iter().any(|x| matches!(x, Effect::AssignCardUid { uid, .. } if uid == "01020304")));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::WriteCard(_))));
+    }
+
+    #[test]
+    fn card_write_fails() {
+        let mut machine = machine(|_| {});
+        let state = playing(&mut machine, classic(Some(0)));
+
+        // the playback continues, only the LED shows the error
+        let (state, effects) = event(&mut machine, state, Event::WriteFailed("timeout".into()));
+        assert_eq!(name(&state), Some("book"));
+        assert!(matches!(&effects[..], [Effect::Error(StoreError::CardWriteFailed(err), _), Effect::Led(_)] if err == "timeout"));
+    }
+
+    #[test]
+    fn card_lost_stops() {
+        let mut machine = machine(|config| config.playback.grace_period = 0);
+        let state = playing(&mut machine, classic(Some(0)));
+
+        let (state, effects) = event(&mut machine, state, Event::CardLost);
+        assert!(matches!(state, State::Idle));
+        assert!(saved(&effects, "book"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Stop)));
+    }
+
+    #[test]
+    fn card_lost_pauses() {
+        let mut machine = machine(|config| config.playback.grace_period = 10);
+        let state = playing(&mut machine, classic(Some(0)));
+
+        let (state, effects) = event(&mut machine, state, Event::CardLost);
+        let until = match state {
+            State::Paused { until: Some(until), .. } => until,
+            ref state => panic!("not paused: {:?}", state),
+        };
+        assert!(effects.iter().any(|x| matches!(x, Effect::Pause)));
+        assert!(saved(&effects, "book"));
+
+        // the playback stops for good once the grace period ended
+        let (state, effects) = machine.handle(state, Input::Tick(until - Duration::from_secs(1)), None);
+        assert!(matches!(state, State::Paused { .. }));
+        assert!(effects.is_empty());
+
+        let (state, effects) = machine.handle(state, Input::Tick(until), None);
+        assert!(matches!(state, State::Idle));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Stop)));
+    }
+
+    #[test]
+    fn paused_card_comes_back() {
+        let mut machine = machine(|config| config.playback.grace_period = 10);
+        let state = playing(&mut machine, classic(Some(0)));
+        let (state, _) = event(&mut machine, state, Event::CardLost);
+
+        let (state, effects) = event(&mut machine, state, Event::NewCard(classic(Some(0))));
+        assert!(matches!(state, State::Playing { .. }));
+        assert_eq!(name(&state), Some("book"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Resume)));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Play { .. })));
+    }
+
+    #[test]
+    fn paused_card_is_replaced() {
+        let mut machine = machine(|config| config.playback.grace_period = 10);
+        let state = playing(&mut machine, classic(Some(0)));
+        let (state, _) = event(&mut machine, state, Event::CardLost);
+
+        let (state, effects) = event(&mut machine, state, Event::NewCard(sticker("04a2b3c4")));
+        assert_eq!(name(&state), Some("figure"));
+        assert!(saved(&effects, "book"));
+
+        let stop = effects.iter().position(|x| matches!(x, Effect::Stop)).unwrap();
+        let play = effects.iter().position(|x| matches!(x, Effect::Play { .. })).unwrap();
+        assert!(stop < play);
+    }
+
+    #[test]
+    fn chord_changes_volume() {
+        let mut machine = machine(|config| {
+            config.volume.max = 60;
+            config.volume.step = 10;
+        });
+        let state = playing(&mut machine, classic(Some(0)));
+
+        let (state, effects) = event(&mut machine, state, Event::Gesture(Gesture::Chord(vec![1, 2])));
+        assert!(matches!(effects[..], [Effect::Volume(60), Effect::ShowVolume(60, 60), _]));
+
+        // louder than the maximum is not possible
+        let (state, effects) = event(&mut machine, state, Event::Gesture(Gesture::Chord(vec![1, 2])));
+        assert!(matches!(effects[..], [Effect::Volume(60), Effect::ShowVolume(60, 60), _]));
+
+        let (state, effects) = event(&mut machine, state, Event::Gesture(Gesture::Chord(vec![0, 1])));
+        assert!(matches!(effects[..], [Effect::Volume(50), Effect::ShowVolume(50, 60), _]));
+
+        // other chords leave the volume alone
+        let (_, effects) = event(&mut machine, state, Event::Gesture(Gesture::Chord(vec![0, 2])));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Volume(_) | Effect::ShowVolume(..))));
+    }
+
+    #[test]
+    fn volume_is_capped() {
+        let mut machine = machine(|config| config.volume.max = 60);
+
+        let (_, effects) = machine.handle(State::Idle, Input::Command(Command::SetVolume(100)), None);
+        assert!(matches!(effects[..], [Effect::Volume(60), Effect::ShowVolume(60, 60), _]));
+
+        // the default and the volume of a playlist are capped as well
+        machine.limits.default = 70;
+        let (_, effects) = event(&mut machine, State::Idle, Event::NewCard(classic(Some(0))));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Volume(60))));
+
+        machine.playlists.playlists[1].volume = Some(90);
+        let (_, effects) = event(&mut machine, State::Idle, Event::NewCard(sticker("04a2b3c4")));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Volume(60))));
+    }
+
+    #[test]
+    fn playlist_volume_on_card() {
+        let mut machine = machine(|_| {});
+        machine.playlists.playlists[1].volume = Some(30);
+        let state = playing(&mut machine, classic(Some(0)));
+        let (_, effects) = event(&mut machine, state, Event::Gesture(Gesture::Chord(vec![1, 2])));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Volume(55))));
+
+        // a card brings the volume of its playlist, or the default one, back
+        let (state, effects) = event(&mut machine, State::Idle, Event::NewCard(sticker("04a2b3c4")));
+        assert_eq!(name(&state), Some("figure"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Volume(30))));
+
+        let (_, effects) = event(&mut machine, State::Idle, Event::NewCard(classic(Some(0))));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Volume(50))));
+    }
+
+    #[test]
+    fn sleep_timer_fades_out() {
+        let mut machine = machine(|config| config.timers.fade = 10);
+        machine.playlists.playlists[0].sleep_timer = Some(SleepTimer { minutes: 1, finish_track: false });
+        let state = playing(&mut machine, classic(Some(0)));
+        let at = match &state {
+            State::Playing { sleep: Some(sleep), .. } => sleep.at,
+            state => panic!("no sleep timer: {:?}", state),
+        };
+        assert!(matches!(led_state(&state), led::State::Continuous(SLEEP_COLOR)));
+
+        let (state, effects) = machine.handle(state, Input::Tick(at - Duration::from_secs(1)), None);
+        assert!(effects.is_empty());
+
+        // half way through the fade, the volume is halved
+        let (state, effects) = machine.handle(state, Input::Tick(at + Duration::from_secs(5)), None);
+        assert!(effects.iter().any(|x| matches!(x, Effect::Volume(25))));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Led(led::State::Sine(SLEEP_COLOR, _)))));
+
+        let (state, effects) = machine.handle(state, Input::Tick(at + Duration::from_secs(10)), Some(Progress { track: 1, elapsed: Duration::from_secs(42) }));
+        assert!(matches!(state, State::Idle));
+        assert!(saved(&effects, "book"));
+        assert!(matches!(effects.iter().filter(|x| matches!(x, Effect::Stop | Effect::Volume(_))).collect::<Vec<_>>()[..],
+            [Effect::Stop, Effect::Volume(50)]));
+    }
+
+    #[test]
+    fn sleep_timer_finishes_track() {
+        let mut machine = machine(|_| {});
+        machine.playlists.playlists[0].sleep_timer = Some(SleepTimer { minutes: 0, finish_track: true });
+        let state = playing(&mut machine, classic(Some(0)));
+
+        // no fade, the next track is not played anymore
+        let (state, effects) = machine.handle(state, Input::Tick(Instant::now() + Duration::from_secs(1)), None);
+        assert!(effects.is_empty());
+
+        let (state, effects) = machine.handle(state, Input::Player(PlayerEvent::TrackChanged(1)), None);
+        assert!(matches!(state, State::Idle));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Stop)));
+    }
+
+    #[test]
+    fn idle_shutdown() {
+        let mut machine = machine(|config| config.timers.idle_shutdown = 1);
+        let start = Instant::now();
+
+        let (state, effects) = machine.handle(State::Idle, Input::Tick(start), None);
+        assert!(effects.is_empty());
+        let (state, effects) = machine.handle(state, Input::Tick(start + Duration::from_secs(59)), None);
+        assert!(effects.is_empty());
+
+        // any input keeps the Zyklop running
+        let (state, _) = event(&mut machine, state, Event::Gesture(Gesture::ShortPress(1)));
+        let (state, effects) = machine.handle(state, Input::Tick(start + Duration::from_secs(61)), None);
+        assert!(effects.is_empty());
+
+        let (_, effects) = machine.handle(state, Input::Tick(start + Duration::from_secs(122)), None);
+        assert!(matches!(effects[..], [Effect::Shutdown]));
+
+        // never while playing
+        let state = playing(&mut machine, classic(Some(0)));
+        let (_, effects) = machine.handle(state, Input::Tick(start + Duration::from_secs(600)), None);
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Shutdown)));
+    }
+
+    #[test]
+    fn power_button_shuts_down() {
+        let mut machine = machine(|_| {});
+        let state = playing(&mut machine, classic(Some(0)));
+
+        // a release without a press, like after booting with the button held, is ignored
+        let (state, effects) = event(&mut machine, state, Event::PowerButton(false));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Shutdown)));
+
+        let (state, effects) = event(&mut machine, state, Event::PowerButton(true));
+        assert!(matches!(state, State::Playing { .. }));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Shutdown)));
+
+        // the player keeps running, the driver fades it out
+        let (state, effects) = event(&mut machine, state, Event::PowerButton(false));
+        assert!(matches!(state, State::Idle));
+        assert!(saved(&effects, "book"));
+        assert!(effects.iter().any(|x| matches!(x, Effect::Shutdown)));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Stop)));
+    }
+
+    #[test]
+    fn power_button_held_quits() {
+        let mut machine = machine(|config| config.power.force_quit = 5);
+        let start = Instant::now();
+
+        let (state, _) = event(&mut machine, State::Idle, Event::PowerButton(true));
+        let (state, effects) = machine.handle(state, Input::Tick(start + Duration::from_secs(3)), None);
+        assert!(effects.is_empty());
+
+        let (state, effects) = machine.handle(state, Input::Tick(start + Duration::from_secs(6)), None);
+        assert!(matches!(effects[..], [Effect::ForceQuit]));
+
+        // releasing the button afterwards does not shut down anymore
+        let (_, effects) = event(&mut machine, state, Event::PowerButton(false));
+        assert!(!effects.iter().any(|x| matches!(x, Effect::Shutdown)));
     }
 }
-*/